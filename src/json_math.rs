@@ -1,22 +1,320 @@
-use std::ops::{self};
+use std::cmp::Ordering;
 use std::convert::From;
 
 use serde_json::Number;
 
 
-#[derive(PartialEq, PartialOrd)]
-pub enum JsonNumber { 
+/// Numeric value of a JSON number. Arithmetic is checked, integer results are
+/// promoted from u64 to i64 and then f64 when they don't fit, and anything that
+/// has no finite result is returned as an error instead of panicking.
+#[derive(Debug, Clone, Copy)]
+pub enum JsonNumber {
     U64(u64),
     I64(i64),
     F64(f64),
 }
 
 impl JsonNumber {
-    pub fn to_number(self) -> Number {
+    pub fn to_number(self) -> Result<Number, String> {
         match self {
-            JsonNumber::U64(n) => Number::from(n),
-            JsonNumber::I64(n) => Number::from(n),
-            JsonNumber::F64(n) => Number::from_f64(n).unwrap()
+            JsonNumber::U64(n) => Ok(Number::from(n)),
+            JsonNumber::I64(n) => Ok(Number::from(n)),
+            JsonNumber::F64(n) => Number::from_f64(n).ok_or(format!("Result is not a finite number: {}", n))
+        }
+    }
+
+    pub fn abs(self) -> JsonNumber {
+        match self {
+            JsonNumber::U64(n) => JsonNumber::U64(n),
+            JsonNumber::I64(n) => JsonNumber::U64(n.unsigned_abs()),
+            JsonNumber::F64(n) => JsonNumber::F64(n.abs()),
+        }
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        match *self {
+            JsonNumber::U64(n) => n as f64,
+            JsonNumber::I64(n) => n as f64,
+            JsonNumber::F64(n) => n,
+        }
+    }
+
+    /// Integer value widened so that any u64 or i64 fits, `None` for floats
+    fn as_i128(&self) -> Option<i128> {
+        match *self {
+            JsonNumber::U64(n) => Some(n as i128),
+            JsonNumber::I64(n) => Some(n as i128),
+            JsonNumber::F64(_) => None,
+        }
+    }
+
+    /// Narrows an integer result to u64, then i64, then f64
+    fn from_i128(n: i128) -> JsonNumber {
+        if let Ok(n) = u64::try_from(n) {
+            JsonNumber::U64(n)
+        } else if let Ok(n) = i64::try_from(n) {
+            JsonNumber::I64(n)
+        } else {
+            JsonNumber::F64(n as f64)
+        }
+    }
+
+    fn finite(n: f64, op: &str) -> Result<JsonNumber, String> {
+        if n.is_finite() {
+            Ok(JsonNumber::F64(n))
+        } else {
+            Err(format!("Numeric overflow in {}", op))
+        }
+    }
+
+    fn checked_arith(
+        self,
+        rhs: JsonNumber,
+        op: &str,
+        int_op: fn(i128, i128) -> Option<i128>,
+        float_op: fn(f64, f64) -> f64,
+    ) -> Result<JsonNumber, String> {
+        if let (Some(n1), Some(n2)) = (self.as_i128(), rhs.as_i128()) {
+            if let Some(res) = int_op(n1, n2) {
+                return Ok(JsonNumber::from_i128(res));
+            }
+        }
+        JsonNumber::finite(float_op(self.as_f64(), rhs.as_f64()), op)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.as_f64() == 0.0
+    }
+
+    fn check_divisor(&self, op: &str) -> Result<(), String> {
+        if self.is_zero() {
+            return Err(format!("Division by zero in {}", op));
+        }
+        Ok(())
+    }
+
+    pub fn checked_neg(self) -> Result<JsonNumber, String> {
+        match self.as_i128() {
+            Some(n) => Ok(JsonNumber::from_i128(-n)),
+            None => Ok(JsonNumber::F64(-self.as_f64())),
+        }
+    }
+
+    pub fn checked_add(self, rhs: JsonNumber) -> Result<JsonNumber, String> {
+        self.checked_arith(rhs, "addition", i128::checked_add, |a, b| a + b)
+    }
+
+    pub fn checked_sub(self, rhs: JsonNumber) -> Result<JsonNumber, String> {
+        self.checked_arith(rhs, "subtraction", i128::checked_sub, |a, b| a - b)
+    }
+
+    pub fn checked_mul(self, rhs: JsonNumber) -> Result<JsonNumber, String> {
+        self.checked_arith(rhs, "multiplication", i128::checked_mul, |a, b| a * b)
+    }
+
+    /// Division stays an integer when both operands are integers and divide evenly
+    pub fn checked_div(self, rhs: JsonNumber) -> Result<JsonNumber, String> {
+        rhs.check_divisor("division")?;
+        self.checked_arith(rhs, "division", |a, b| {
+            if a % b == 0 { a.checked_div(b) } else { None }
+        }, |a, b| a / b)
+    }
+
+    /// Remainder takes the sign of the dividend
+    pub fn checked_rem(self, rhs: JsonNumber) -> Result<JsonNumber, String> {
+        rhs.check_divisor("modulo")?;
+        self.checked_arith(rhs, "modulo", i128::checked_rem, |a, b| a % b)
+    }
+
+    /// Division truncated toward zero
+    pub fn checked_int_div(self, rhs: JsonNumber) -> Result<JsonNumber, String> {
+        rhs.check_divisor("integer division")?;
+        let res = self.checked_arith(rhs, "integer division", i128::checked_div, |a, b| (a / b).trunc())?;
+        Ok(res.to_integer())
+    }
+
+    /// Integer powers stay integers while they fit, negative or fractional exponents use f64
+    pub fn checked_pow(self, rhs: JsonNumber) -> Result<JsonNumber, String> {
+        if self.is_zero() && rhs.as_f64() < 0.0 {
+            return Err("Division by zero in exponent".to_string());
+        }
+
+        self.checked_arith(rhs, "exponent", |a, b| {
+            u32::try_from(b).ok().and_then(|b| a.checked_pow(b))
+        }, f64::powf)
+    }
+
+    /// Operand of a bitwise operation, floats are an error even when whole
+    fn bits(self, op: &str) -> Result<i128, String> {
+        self.as_i128().ok_or(format!("Bitwise {} requires integers, got {}", op, self.as_f64()))
+    }
+
+    /// Bitwise results must fit u64 or i64, there is no float to fall back to
+    fn from_bits(n: i128, op: &str) -> Result<JsonNumber, String> {
+        match JsonNumber::from_i128(n) {
+            JsonNumber::F64(_) => Err(format!("Numeric overflow in bitwise {}", op)),
+            res => Ok(res),
+        }
+    }
+
+    fn checked_bitwise(self, rhs: JsonNumber, op: &str, bit_op: fn(i128, i128) -> i128) -> Result<JsonNumber, String> {
+        JsonNumber::from_bits(bit_op(self.bits(op)?, rhs.bits(op)?), op)
+    }
+
+    /// Negative operands act as 64 bit two's complement values
+    pub fn checked_bitand(self, rhs: JsonNumber) -> Result<JsonNumber, String> {
+        self.checked_bitwise(rhs, "AND", |a, b| a & b)
+    }
+
+    pub fn checked_bitor(self, rhs: JsonNumber) -> Result<JsonNumber, String> {
+        self.checked_bitwise(rhs, "OR", |a, b| a | b)
+    }
+
+    pub fn checked_bitxor(self, rhs: JsonNumber) -> Result<JsonNumber, String> {
+        self.checked_bitwise(rhs, "XOR", |a, b| a ^ b)
+    }
+
+    /// Complement, so `~5` is `-6`
+    pub fn checked_bitnot(self) -> Result<JsonNumber, String> {
+        JsonNumber::from_bits(!self.bits("NOT")?, "NOT")
+    }
+
+    fn shift_amount(self, op: &str) -> Result<u32, String> {
+        let shift = self.bits(op)?;
+        u32::try_from(shift).map(|x| x.min(127)).map_err(|_| format!("Bitwise {} by a negative amount: {}", op, shift))
+    }
+
+    /// Shifting bits out of the range of u64 and i64 is an overflow
+    pub fn checked_shl(self, rhs: JsonNumber) -> Result<JsonNumber, String> {
+        let (n, shift) = (self.bits("shift left")?, rhs.shift_amount("shift left")?);
+        match n {
+            0 => Ok(JsonNumber::U64(0)),
+            _ if shift >= 64 => Err("Numeric overflow in bitwise shift left".to_string()),
+            _ => JsonNumber::from_bits(n << shift, "shift left"),
+        }
+    }
+
+    /// Arithmetic shift, negative numbers stay negative
+    pub fn checked_shr(self, rhs: JsonNumber) -> Result<JsonNumber, String> {
+        let (n, shift) = (self.bits("shift right")?, rhs.shift_amount("shift right")?);
+        JsonNumber::from_bits(n >> shift, "shift right")
+    }
+
+    /// Number of set bits, negative numbers count as their 64 bit two's complement
+    pub fn bit_count(self) -> Result<JsonNumber, String> {
+        let n = self.bits("count")?;
+        let count = if n < 0 { (n as i64).count_ones() } else { (n as u64).count_ones() };
+        Ok(JsonNumber::U64(count as u64))
+    }
+
+    /// Bit at `position`, counting from 0 for the least significant bit
+    pub fn get_bit(self, position: JsonNumber) -> Result<JsonNumber, String> {
+        let n = self.bits("get")?;
+        match position.bits("get")? {
+            position @ 0..=63 => Ok(JsonNumber::U64(((n >> position) & 1) as u64)),
+            position => Err(format!("Bit position must be between 0 and 63, got {}", position)),
+        }
+    }
+
+    /// Rounds half away from zero to `digits` decimal places, negative digits round
+    /// to tens, hundreds and so on. Integers stay integers, as do floats rounded to
+    /// a whole number.
+    pub fn round(self, digits: i64) -> JsonNumber {
+        self.round_digits(digits, true, f64::round)
+    }
+
+    /// Truncates toward zero to `digits` decimal places, like `round`
+    pub fn trunc(self, digits: i64) -> JsonNumber {
+        self.round_digits(digits, false, f64::trunc)
+    }
+
+    pub fn floor(self) -> JsonNumber {
+        self.round_digits(0, false, f64::floor)
+    }
+
+    pub fn ceil(self) -> JsonNumber {
+        self.round_digits(0, false, f64::ceil)
+    }
+
+    fn round_digits(self, digits: i64, half_up: bool, float_op: fn(f64) -> f64) -> JsonNumber {
+        if let Some(n) = self.as_i128() {
+            if digits >= 0 {
+                return self;
+            }
+            let scale = match u32::try_from(-digits).ok().and_then(|exp| 10i128.checked_pow(exp)) {
+                Some(scale) => scale,
+                None => return JsonNumber::U64(0),
+            };
+            let (quotient, remainder) = (n / scale, n % scale);
+            let quotient = if half_up && remainder.abs() * 2 >= scale { quotient + remainder.signum() } else { quotient };
+            return JsonNumber::from_i128(quotient * scale);
+        }
+
+        let n = self.as_f64();
+        let scale = 10f64.powi(digits.unsigned_abs().min(400) as i32);
+        let res = if digits >= 0 {
+            // Past the precision of f64 there is nothing left to round
+            if (n * scale).is_finite() { float_op(n * scale) / scale } else { n }
+        } else if scale.is_finite() {
+            float_op(n / scale) * scale
+        } else {
+            0.0
+        };
+
+        if digits <= 0 { JsonNumber::F64(res).to_integer() } else { JsonNumber::F64(res) }
+    }
+
+    /// Square root, exact for integer squares
+    pub fn checked_sqrt(self) -> Result<JsonNumber, String> {
+        if self.as_f64() < 0.0 {
+            return Err(format!("Square root of a negative number: {}", self.as_f64()));
+        }
+
+        let root = self.as_f64().sqrt();
+        match self.as_i128() {
+            Some(n) if (root.round() as i128).pow(2) == n => Ok(JsonNumber::from_i128(root.round() as i128)),
+            _ => Ok(JsonNumber::F64(root)),
+        }
+    }
+
+    /// Applies a float function, a NaN result means the argument is outside its domain
+    pub fn checked_apply(self, op: &str, float_op: fn(f64) -> f64) -> Result<JsonNumber, String> {
+        let res = float_op(self.as_f64());
+        if res.is_nan() {
+            return Err(format!("Argument of {} out of range: {}", op, self.as_f64()));
+        }
+        JsonNumber::finite(res, op)
+    }
+
+    /// -1, 0 or 1
+    pub fn signum(self) -> JsonNumber {
+        match self.partial_cmp(&JsonNumber::U64(0)) {
+            Some(Ordering::Less) => JsonNumber::I64(-1),
+            Some(Ordering::Greater) => JsonNumber::U64(1),
+            _ => JsonNumber::U64(0),
+        }
+    }
+
+    /// Converts a float holding a whole number back to an integer variant
+    pub fn to_integer(self) -> JsonNumber {
+        match self {
+            JsonNumber::F64(n) if n.fract() == 0.0 && n.abs() < 2f64.powi(63) => JsonNumber::from_i128(n as i128),
+            other => other,
+        }
+    }
+}
+
+impl PartialEq for JsonNumber {
+    fn eq(&self, other: &JsonNumber) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for JsonNumber {
+    fn partial_cmp(&self, other: &JsonNumber) -> Option<Ordering> {
+        match (self.as_i128(), other.as_i128()) {
+            (Some(n1), Some(n2)) => Some(n1.cmp(&n2)),
+            _ => self.as_f64().partial_cmp(&other.as_f64()),
         }
     }
 }
@@ -52,49 +350,3 @@ impl From<f64> for JsonNumber {
         JsonNumber::F64(number)
     }
 }
-
-macro_rules! json_number_arith {
-    ($e1:ident $op:tt $e2:ident) => {
-        match ($e1, $e2) {
-            (JsonNumber::U64(n1), JsonNumber::U64(n2)) => JsonNumber::from(n1 $op n2),
-            (JsonNumber::U64(n1), JsonNumber::I64(n2)) => JsonNumber::from(n1 as i64 $op n2),
-            (JsonNumber::U64(n1), JsonNumber::F64(n2)) => JsonNumber::from(n1 as f64 $op n2),
-            (JsonNumber::I64(n1), JsonNumber::U64(n2)) => JsonNumber::from(n1 $op n2 as i64),
-            (JsonNumber::I64(n1), JsonNumber::I64(n2)) => JsonNumber::from(n1 $op n2),
-            (JsonNumber::I64(n1), JsonNumber::F64(n2)) => JsonNumber::from(n1 as f64 $op n2),
-            (JsonNumber::F64(n1), JsonNumber::U64(n2)) => JsonNumber::from(n1 $op n2 as f64),
-            (JsonNumber::F64(n1), JsonNumber::I64(n2)) => JsonNumber::from(n1 $op n2 as f64),
-            (JsonNumber::F64(n1), JsonNumber::F64(n2)) => JsonNumber::from(n1 $op n2)
-        }
-    };
-}
-
-
-impl ops::Add<JsonNumber> for JsonNumber {
-    type Output = JsonNumber;
-
-    fn add(self, rhs: JsonNumber) -> JsonNumber {
-        json_number_arith!(self + rhs)
-    }
-}
-
-impl ops::Sub<JsonNumber> for JsonNumber {
-    type Output = JsonNumber;
-    fn sub(self, rhs: JsonNumber) -> JsonNumber {
-        json_number_arith!(self - rhs)
-    }
-}
-
-impl ops::Mul<JsonNumber> for JsonNumber {
-    type Output = JsonNumber;
-    fn mul(self, rhs: JsonNumber) -> JsonNumber {
-        json_number_arith!(self * rhs)
-    }
-}
-
-impl ops::Div<JsonNumber> for JsonNumber {
-    type Output = JsonNumber;
-    fn div(self, rhs: JsonNumber) -> JsonNumber {
-        json_number_arith!(self / rhs)
-    }
-}
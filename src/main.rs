@@ -83,4 +83,28 @@ fn main() {
         println!("Output Valid: {:?}", valid);
         
     }
+
+    let compiled_statement = "SELECT device_id, payload.version AS version FROM \"/devices/{device_id}/telemetry\" WHERE payload.version >= 1".to_string();
+
+    println!("\nCOMPILED SQL:\n{}\n", compiled_statement);
+
+    let queries = match sql::compile(compiled_statement) {
+        Ok(queries) => queries,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
+
+    // Compiled once, each message only reuses the context
+    for query in &queries {
+        let mut context = query.new_context();
+        for version in 0..3 {
+            let topic = format!("/devices/{}/telemetry", version);
+            match query.execute_on(&mut context, &topic, &serde_json::json!({ "version": version }).into()) {
+                Ok(res) => sql::debug::print_query_result(&res),
+                Err(err) => println!("Error: {}", err),
+            }
+        }
+    }
 }
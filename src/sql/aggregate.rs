@@ -0,0 +1,316 @@
+use std::collections::{HashMap, HashSet};
+
+use phf::phf_map;
+use sqlparser::ast::BinaryOperator;
+
+use crate::json_math::JsonNumber;
+
+use super::execute;
+use super::time::Timestamp;
+use super::types::{SQLValue, ValueKey};
+
+/// Aggregate functions with the least and most arguments they take
+static AGGREGATES: phf::Map<&'static str, (usize, usize)> = phf_map! {
+    "COUNT" => (0, 1),
+    "SUM" => (1, 1),
+    "AVG" => (1, 1),
+    "MIN" => (1, 1),
+    "MAX" => (1, 1),
+    "ARRAY_AGG" => (1, 1),
+    "STRING_AGG" => (2, 2),
+    "ANY_VALUE" => (1, 1),
+    "WINDOW_START" => (0, 0),
+    "WINDOW_END" => (0, 0),
+};
+
+pub fn is_aggregate(name: &str) -> bool {
+    AGGREGATES.contains_key(name)
+}
+
+/// Bounds of the window a group is in, only known to windowed queries
+pub fn is_window_bound(name: &str) -> bool {
+    name == "WINDOW_START" || name == "WINDOW_END"
+}
+
+pub fn check_arg_count(name: &str, count: usize) -> Result<(), String> {
+    let (min_args, max_args) = AGGREGATES.get(name).ok_or(format!("Unknown aggregate function: {}", name))?;
+    if count < *min_args || count > *max_args {
+        return Err(format!("Aggregate {} expects {} to {} arguments, got {}", name, min_args, max_args, count));
+    }
+    Ok(())
+}
+
+/// Running state of an aggregate over the rows of a FOREACH.
+///
+/// NULL and MISSING arguments are skipped, except by `COUNT(*)` which counts
+/// every row, `ARRAY_AGG` which keeps NULL and `ANY_VALUE` which keeps the first
+/// value whatever it is. Without any value left COUNT is 0 and every other
+/// aggregate is NULL. A DISTINCT aggregate only sees the first of equal values
+/// of its first argument. WINDOW_START and WINDOW_END take no rows, they are set
+/// to the bounds of their window when it opens.
+#[derive(Debug, Clone)]
+pub enum Accumulator {
+    CountRows(u64),
+    Count(u64),
+    Sum(Option<JsonNumber>),
+    Avg(Option<(JsonNumber, u64)>),
+    Min(Option<SQLValue>),
+    Max(Option<SQLValue>),
+    ArrayAgg(Option<Vec<serde_json::Value>>),
+    /// Values joined so far with the last delimiter seen
+    StringAgg(Option<String>, Option<String>),
+    AnyValue(Option<SQLValue>),
+    /// Keys of the first arguments seen and the arguments of the rows they came
+    /// from, in order
+    Distinct(HashSet<ValueKey>, Vec<Vec<SQLValue>>, Box<Accumulator>),
+    WindowStart(Option<Timestamp>),
+    WindowEnd(Option<Timestamp>),
+}
+
+impl Accumulator {
+    /// Empty state of the aggregate `name` called with `arg_count` arguments
+    pub fn new(name: &str, arg_count: usize, distinct: bool) -> Result<Accumulator, String> {
+        if distinct {
+            return Ok(Accumulator::Distinct(HashSet::new(), Vec::new(), Box::new(Accumulator::new(name, arg_count, false)?)));
+        }
+
+        match name {
+            "COUNT" if arg_count == 0 => Ok(Accumulator::CountRows(0)),
+            "COUNT" => Ok(Accumulator::Count(0)),
+            "SUM" => Ok(Accumulator::Sum(None)),
+            "AVG" => Ok(Accumulator::Avg(None)),
+            "MIN" => Ok(Accumulator::Min(None)),
+            "MAX" => Ok(Accumulator::Max(None)),
+            "ARRAY_AGG" => Ok(Accumulator::ArrayAgg(None)),
+            "STRING_AGG" => Ok(Accumulator::StringAgg(None, None)),
+            "ANY_VALUE" => Ok(Accumulator::AnyValue(None)),
+            "WINDOW_START" => Ok(Accumulator::WindowStart(None)),
+            "WINDOW_END" => Ok(Accumulator::WindowEnd(None)),
+            _ => Err(format!("Unknown aggregate function: {}", name)),
+        }
+    }
+
+    /// Gives WINDOW_START and WINDOW_END the bounds of their window
+    pub fn in_window(self, start: Timestamp, end: Timestamp) -> Accumulator {
+        match self {
+            Accumulator::WindowStart(_) => Accumulator::WindowStart(Some(start)),
+            Accumulator::WindowEnd(_) => Accumulator::WindowEnd(Some(end)),
+            other => other,
+        }
+    }
+
+    /// Adds the arguments of one row
+    pub fn update(&mut self, args: &[&SQLValue]) -> Result<(), String> {
+        if let Accumulator::WindowStart(_) | Accumulator::WindowEnd(_) = self {
+            return Ok(());
+        }
+
+        if let Accumulator::Distinct(seen, rows, inner) = self {
+            if !seen.insert(ValueKey::from(args[0])) {
+                return Ok(());
+            }
+            rows.push(args.iter().map(|x| (*x).clone()).collect());
+            return inner.update(args);
+        }
+
+        if let Accumulator::CountRows(count) = self {
+            *count += 1;
+            return Ok(());
+        }
+
+        if let Accumulator::AnyValue(value) = self {
+            value.get_or_insert_with(|| args[0].clone());
+            return Ok(());
+        }
+
+        if let Accumulator::ArrayAgg(elems) = self {
+            if !args[0].is_missing() {
+                elems.get_or_insert_with(Vec::new).push(args[0].clone().into_json());
+            }
+            return Ok(());
+        }
+
+        let value = args[0];
+        if value.is_unknown() {
+            return Ok(());
+        }
+
+        match self {
+            Accumulator::Count(count) => *count += 1,
+            Accumulator::Sum(sum) => {
+                let n = number(value, "SUM")?;
+                *sum = Some(match sum {
+                    Some(sum) => sum.checked_add(n)?,
+                    None => n,
+                });
+            },
+            Accumulator::Avg(avg) => {
+                let n = number(value, "AVG")?;
+                *avg = Some(match avg {
+                    Some((sum, count)) => (sum.checked_add(n)?, *count + 1),
+                    None => (n, 1),
+                });
+            },
+            Accumulator::Min(min) => replace_if(min, value, &BinaryOperator::Lt, "MIN")?,
+            Accumulator::Max(max) => replace_if(max, value, &BinaryOperator::Gt, "MAX")?,
+            Accumulator::StringAgg(joined, last_delimiter) => {
+                let s = match value.as_json() {
+                    Some(serde_json::Value::String(s)) => s,
+                    _ => return Err(format!("Aggregate STRING_AGG expects strings, got {}", value.clone().into_json())),
+                };
+                let delimiter = match args[1].as_json() {
+                    Some(serde_json::Value::String(delimiter)) => Some(delimiter),
+                    _ if args[1].is_unknown() => None,
+                    _ => return Err(format!("Aggregate STRING_AGG delimiter must be a string, got {}", args[1].clone().into_json())),
+                };
+                match joined {
+                    Some(joined) => {
+                        joined.push_str(delimiter.map_or("", |x| x.as_str()));
+                        joined.push_str(s);
+                    },
+                    None => *joined = Some(s.clone()),
+                }
+                *last_delimiter = delimiter.cloned();
+            },
+            Accumulator::CountRows(_) | Accumulator::ArrayAgg(_) | Accumulator::AnyValue(_) | Accumulator::Distinct(..)
+                | Accumulator::WindowStart(_) | Accumulator::WindowEnd(_) => unreachable!(),
+        }
+
+        Ok(())
+    }
+
+    /// Adds the rows of `other`, the same aggregate over rows that came after.
+    /// STRING_AGG joins the two with the last delimiter `other` saw.
+    pub fn merge(&mut self, other: Accumulator) -> Result<(), String> {
+        match (self, other) {
+            (Accumulator::CountRows(count), Accumulator::CountRows(other)) | (Accumulator::Count(count), Accumulator::Count(other)) => *count += other,
+            (Accumulator::Sum(sum), Accumulator::Sum(Some(other))) => {
+                *sum = Some(match sum {
+                    Some(sum) => sum.checked_add(other)?,
+                    None => other,
+                });
+            },
+            (Accumulator::Avg(avg), Accumulator::Avg(Some((other_sum, other_count)))) => {
+                *avg = Some(match avg {
+                    Some((sum, count)) => (sum.checked_add(other_sum)?, *count + other_count),
+                    None => (other_sum, other_count),
+                });
+            },
+            (Accumulator::Min(min), Accumulator::Min(Some(other))) => replace_if(min, &other, &BinaryOperator::Lt, "MIN")?,
+            (Accumulator::Max(max), Accumulator::Max(Some(other))) => replace_if(max, &other, &BinaryOperator::Gt, "MAX")?,
+            (Accumulator::ArrayAgg(elems), Accumulator::ArrayAgg(Some(other))) => elems.get_or_insert_with(Vec::new).extend(other),
+            (Accumulator::StringAgg(joined, last_delimiter), Accumulator::StringAgg(Some(other), other_delimiter)) => {
+                match joined {
+                    Some(joined) => {
+                        joined.push_str(other_delimiter.as_deref().unwrap_or(""));
+                        joined.push_str(&other);
+                    },
+                    None => *joined = Some(other),
+                }
+                *last_delimiter = other_delimiter;
+            },
+            (Accumulator::AnyValue(value), Accumulator::AnyValue(other)) => {
+                if value.is_none() {
+                    *value = other;
+                }
+            },
+            (this @ Accumulator::Distinct(..), Accumulator::Distinct(_, rows, _)) => {
+                for row in rows {
+                    this.update(&row.iter().collect::<Vec<_>>())?;
+                }
+            },
+            (Accumulator::WindowStart(_), Accumulator::WindowStart(_)) | (Accumulator::WindowEnd(_), Accumulator::WindowEnd(_)) => {},
+            // Nothing to add from an empty aggregate
+            (Accumulator::Sum(_), Accumulator::Sum(None)) | (Accumulator::Avg(_), Accumulator::Avg(None))
+                | (Accumulator::Min(_), Accumulator::Min(None)) | (Accumulator::Max(_), Accumulator::Max(None))
+                | (Accumulator::ArrayAgg(_), Accumulator::ArrayAgg(None)) | (Accumulator::StringAgg(..), Accumulator::StringAgg(None, _)) => {},
+            (this, other) => return Err(format!("Can't merge aggregates {:?} and {:?}", this, other)),
+        }
+        Ok(())
+    }
+
+    /// Result over the rows added so far
+    pub fn finish(&self) -> Result<SQLValue, String> {
+        let null = SQLValue::from(serde_json::Value::Null);
+        let number_value = |n: JsonNumber| -> Result<SQLValue, String> { Ok(SQLValue::from(serde_json::Value::Number(n.to_number()?))) };
+
+        match self {
+            Accumulator::CountRows(count) | Accumulator::Count(count) => number_value(JsonNumber::from(*count)),
+            Accumulator::Sum(sum) => sum.map_or(Ok(null), number_value),
+            Accumulator::Avg(avg) => avg.map_or(Ok(null), |(sum, count)| number_value(sum.checked_div(JsonNumber::from(count))?)),
+            Accumulator::Min(value) | Accumulator::Max(value) | Accumulator::AnyValue(value) => Ok(value.clone().unwrap_or(null)),
+            Accumulator::ArrayAgg(elems) => Ok(elems.clone().map_or(null, |x| SQLValue::from(serde_json::Value::Array(x)))),
+            Accumulator::StringAgg(joined, _) => Ok(joined.clone().map_or(null, |x| SQLValue::from(serde_json::Value::String(x)))),
+            Accumulator::Distinct(_, _, inner) => inner.finish(),
+            Accumulator::WindowStart(bound) | Accumulator::WindowEnd(bound) => Ok(bound.map_or(null, SQLValue::Timestamp)),
+        }
+    }
+}
+
+/// Accumulators of the groups of an aggregate query, in order of first appearance
+#[derive(Debug, Clone)]
+pub struct Groups {
+    empty: Vec<Accumulator>,
+    pub accumulators: Vec<Vec<Accumulator>>,
+    positions: HashMap<Vec<ValueKey>, usize>,
+}
+
+impl Groups {
+    /// No groups yet, each starts from the `empty` accumulators
+    pub fn new(empty: Vec<Accumulator>) -> Self {
+        Groups { empty, accumulators: Vec::new(), positions: HashMap::new() }
+    }
+
+    /// Adds the groups of `other`, merging the groups both have
+    pub fn merge(&mut self, other: Groups) -> Result<(), String> {
+        let mut positions = other.positions.into_iter().collect::<Vec<_>>();
+        positions.sort_by_key(|x| x.1);
+        for ((key, _), group) in positions.into_iter().zip(other.accumulators) {
+            match self.positions.get(&key) {
+                Some(position) => {
+                    for (accumulator, other) in self.accumulators[*position].iter_mut().zip(group) {
+                        accumulator.merge(other)?;
+                    }
+                },
+                None => {
+                    self.positions.insert(key, self.accumulators.len());
+                    self.accumulators.push(group);
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Accumulators of the group with the key values `key`, added the first time it is seen
+    pub fn group_mut(&mut self, key: Vec<ValueKey>) -> &mut Vec<Accumulator> {
+        let position = *self.positions.entry(key).or_insert_with(|| {
+            self.accumulators.push(self.empty.clone());
+            self.accumulators.len() - 1
+        });
+        &mut self.accumulators[position]
+    }
+}
+
+fn number(value: &SQLValue, name: &str) -> Result<JsonNumber, String> {
+    match value.as_json() {
+        Some(serde_json::Value::Number(n)) => Ok(JsonNumber::from(n)),
+        _ => Err(format!("Aggregate {} expects numbers, got {}", name, value.clone().into_json())),
+    }
+}
+
+/// Keeps `value` when it compares to the current one with `op`, values that
+/// can't be ordered against each other are an error
+fn replace_if(current: &mut Option<SQLValue>, value: &SQLValue, op: &BinaryOperator, name: &str) -> Result<(), String> {
+    let replace = match current {
+        Some(current) => match execute::execute_binary_op((value, current), op)?.as_json() {
+            Some(serde_json::Value::Bool(replace)) => *replace,
+            _ => return Err(format!("Aggregate {} can't order {} and {}", name, value.clone().into_json(), current.clone().into_json())),
+        },
+        None => true,
+    };
+
+    if replace {
+        *current = Some(value.clone());
+    }
+    Ok(())
+}
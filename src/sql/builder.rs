@@ -1,15 +1,19 @@
 use petgraph::graph::NodeIndex;
 use sqlparser::ast::{self, ForeachStatement, GroupByExpr};
+use super::dialect::ForeachClauses;
+use super::aggregate;
 use super::graph;
-use super::types::{BuiltQueryForeach, QuerySelect};
+use super::topic::TopicFilter;
+use super::window::{self, WindowSpec};
+use super::types::{BuiltQueryForeach, QuerySelect, SortKey};
 use super::{types::{BuiltQuerySelect, QueryTask, TaskAction}, sqlparser_helper::get_table_name};
 
-pub fn build_select_query(select_query: Box<ast::Select>, root_alias: String) -> Result<BuiltQuerySelect, String> { 
+pub fn build_select_query(select_query: Box<ast::Select>, root_alias: String, order_by: &[ast::OrderByExpr]) -> Result<BuiltQuerySelect, String> { 
     let mut query = BuiltQuerySelect::new();
     let task_graph = &mut query.task_graph;
 
     // ROOT NODE NEEDS IDX 0 - DO NOT MOVE THIS LINE
-    let _root_node = task_graph.add_node(QueryTask {
+    let root_node = task_graph.add_node(QueryTask {
         alias: None,
         action: TaskAction::Root,
         required: true,
@@ -23,13 +27,62 @@ pub fn build_select_query(select_query: Box<ast::Select>, root_alias: String) ->
         context: None,
     });
 
+    let topic_node = task_graph.add_node(QueryTask {
+        alias: None,
+        action: TaskAction::Topic,
+        required: true,
+        context: None,
+    });
+
     let select_items = select_query
         .projection
         .iter()
         .map(|x| graph::add_select_item(task_graph, x.clone()))
         .collect::<Result<Vec<NodeIndex>, String>>()?;
 
-    let from_table = get_table_name(select_query.from.first().unwrap().clone())?;
+    let from_table = get_table_name(select_query.from.first().ok_or("Query needs a FROM topic".to_string())?.clone())?.parse::<TopicFilter>()?;
+
+    let group_by_exprs = match &select_query.group_by {
+        GroupByExpr::Expressions(exprs) => exprs,
+        GroupByExpr::All => return Err("GROUP BY ALL is not supported".to_string()),
+    };
+
+    let mut window = None;
+    let mut group_by = Vec::<NodeIndex>::new();
+    for expr in group_by_exprs {
+        match window::window_function(expr) {
+            Some(_) if window.is_some() => return Err(format!("GROUP BY takes a single window: {}", expr)),
+            Some(window_function) => {
+                let (time, kind) = window_function?;
+                window = Some(WindowSpec { time: graph::add_expr(task_graph, time, None)?, kind });
+            },
+            None => group_by.push(graph::add_expr(task_graph, expr.clone(), None)?),
+        }
+    }
+
+    let having = match &select_query.having {
+        Some(having) if group_by.is_empty() && window.is_none() => return Err(format!("HAVING requires GROUP BY: {}", having)),
+        Some(having) => Some(graph::add_expr(task_graph, having.clone(), None)?),
+        None => None,
+    };
+
+    let order_by = order_by
+        .iter()
+        .map(|x| {
+            let descending = x.asc == Some(false);
+            Ok(SortKey {
+                expr: graph::add_expr(task_graph, x.expr.clone(), None)?,
+                descending,
+                nulls_first: x.nulls_first.unwrap_or(descending),
+            })
+        })
+        .collect::<Result<Vec<SortKey>, String>>()?;
+
+    let distinct = match &select_query.distinct {
+        Some(ast::Distinct::Distinct) => true,
+        Some(ast::Distinct::On(_)) => return Err("DISTINCT ON is not supported".to_string()),
+        None => false,
+    };
 
     let mut where_expr: Option<NodeIndex> = None;
     if select_query.selection.is_some() {
@@ -53,7 +106,14 @@ pub fn build_select_query(select_query: Box<ast::Select>, root_alias: String) ->
         task_graph.add_edge(where_expr.unwrap(), final_node, 1);
     }
 
-    graph::dealias(task_graph, root_alias)?;
+    let window_time = window.as_ref().map(|x| x.time);
+    group_by.iter().chain(having.iter()).chain(order_by.iter().map(|x| &x.expr)).chain(window_time.iter()).for_each(|idx| {
+        task_graph.node_weight_mut(*idx).unwrap().required = true;
+        task_graph.add_edge(*idx, final_node, 1);
+    });
+
+    graph::dealias(task_graph, root_alias, &from_table.captures())?;
+    graph::link_group_keys(task_graph, &group_by)?;
 
     // Get output aliases names
     select_items
@@ -70,19 +130,31 @@ pub fn build_select_query(select_query: Box<ast::Select>, root_alias: String) ->
             }
         });
 
+    query.root = root_node;
+    query.topic = Some(topic_node);
     query.query_select = Some(QuerySelect {
         select_items: select_items,
+        distinct: distinct,
         from: from_table,
         where_expr: where_expr,
+        group_by: group_by,
+        window: window,
+        having: having,
+        order_by: order_by,
+        limit: None,
+        offset: 0,
     });
 
     // Build execution plan
     query.initalize_execution_context()?;
+    if !query.is_windowed() && query.aggregates.iter().any(|x| matches!(&query.task_graph[*x].action, TaskAction::Aggregate { name, .. } if aggregate::is_window_bound(name))) {
+        return Err("WINDOW_START and WINDOW_END are only supported in windowed SELECT".to_string());
+    }
     graph::print_graph(&query.task_graph);
     Ok(query)
 }
 
-pub fn build_foreach_query(foreach_query: &ForeachStatement) -> Result<BuiltQueryForeach, String> {
+pub fn build_foreach_query(foreach_query: &ForeachStatement, clauses: ForeachClauses) -> Result<BuiltQueryForeach, String> {
     
     let main_body_select = Box::new(ast::Select {
         distinct: None,
@@ -92,7 +164,7 @@ pub fn build_foreach_query(foreach_query: &ForeachStatement) -> Result<BuiltQuer
         from: vec![foreach_query.from_table.clone()],
         lateral_views: vec![],
         selection: foreach_query.where_expr.clone(),
-        group_by: GroupByExpr::All,
+        group_by: GroupByExpr::Expressions(vec![]),
         having: None,
         cluster_by: vec![],
         distribute_by: vec![],
@@ -107,15 +179,15 @@ pub fn build_foreach_query(foreach_query: &ForeachStatement) -> Result<BuiltQuer
     };
     
     let foreach_select = Box::new(ast::Select {
-        distinct: None,
+        distinct: clauses.distinct,
         top: None,
         projection: return_items_select,
         into: None,
         from: vec![foreach_query.from_table.clone()],
         lateral_views: vec![],
         selection: foreach_query.when_expr.clone(),
-        group_by: GroupByExpr::All,
-        having: None,
+        group_by: GroupByExpr::Expressions(clauses.group_by),
+        having: clauses.having,
         cluster_by: vec![],
         distribute_by: vec![],
         sort_by: vec![],
@@ -133,8 +205,35 @@ pub fn build_foreach_query(foreach_query: &ForeachStatement) -> Result<BuiltQuer
         _ => Err("Select item must have an alias".to_string())
     }?;
 
-    let main_built =    build_select_query(main_body_select, "payload".to_string())?;
-    let foreach_built = build_select_query(foreach_select, alias)?;
+    let limit = match &clauses.limit {
+        Some(limit) => Some(row_count(limit, "LIMIT")?),
+        None => None
+    };
+    let offset = match &clauses.offset {
+        Some(offset) => row_count(&offset.value, "OFFSET")?,
+        None => 0
+    };
+
+    let main_built =    build_select_query(main_body_select, "payload".to_string(), &[])?;
+    if main_built.is_aggregate() {
+        return Err("Aggregate functions are only supported in FOREACH RETURN".to_string());
+    }
+    let mut foreach_built = build_select_query(foreach_select, alias, &clauses.order_by)?;
+    if foreach_built.is_windowed() {
+        return Err("Windows are only supported in SELECT".to_string());
+    }
+    if let Some(query_select) = foreach_built.query_select.as_mut() {
+        query_select.limit = limit;
+        query_select.offset = offset;
+    }
 
     Ok(BuiltQueryForeach::new(main_built, foreach_built))
-}
\ No newline at end of file
+}
+
+/// Number of rows given to LIMIT or OFFSET, which must be a non-negative integer
+fn row_count(expr: &ast::Expr, clause: &str) -> Result<usize, String> {
+    match expr {
+        ast::Expr::Value(ast::Value::Number(n, _)) => n.parse::<usize>().ok(),
+        _ => None
+    }.ok_or(format!("{} must be a non-negative integer, got {}", clause, expr))
+}
@@ -0,0 +1,145 @@
+use sqlparser::ast::DataType;
+
+use crate::json_math::JsonNumber;
+
+use super::schema::SchemaNode;
+use super::time;
+use super::types::{CastType, SQLValue};
+
+pub fn cast_type(data_type: &DataType) -> Result<CastType, String> {
+    match data_type {
+        DataType::Bool | DataType::Boolean => Ok(CastType::Bool),
+
+        DataType::TinyInt(_) | DataType::UnsignedTinyInt(_)
+        | DataType::SmallInt(_) | DataType::UnsignedSmallInt(_)
+        | DataType::MediumInt(_) | DataType::UnsignedMediumInt(_)
+        | DataType::Int(_) | DataType::UnsignedInt(_)
+        | DataType::Integer(_) | DataType::UnsignedInteger(_)
+        | DataType::BigInt(_) | DataType::UnsignedBigInt(_)
+        | DataType::Int2(_) | DataType::UnsignedInt2(_)
+        | DataType::Int4(_) | DataType::UnsignedInt4(_)
+        | DataType::Int8(_) | DataType::UnsignedInt8(_)
+        | DataType::Int64 => Ok(CastType::Integer),
+
+        DataType::Float(_) | DataType::Float4 | DataType::Float8 | DataType::Float64
+        | DataType::Real | DataType::Double | DataType::DoublePrecision
+        | DataType::Numeric(_) | DataType::Decimal(_) | DataType::Dec(_)
+        | DataType::BigNumeric(_) | DataType::BigDecimal(_) => Ok(CastType::Number),
+
+        DataType::Text | DataType::String(_)
+        | DataType::Varchar(_) | DataType::Nvarchar(_)
+        | DataType::Char(_) | DataType::Character(_)
+        | DataType::CharVarying(_) | DataType::CharacterVarying(_) => Ok(CastType::String),
+
+        DataType::Timestamp(_, _) | DataType::Datetime(_) | DataType::Date => Ok(CastType::Timestamp),
+        DataType::Interval => Ok(CastType::Interval),
+
+        DataType::Array(_) => Ok(CastType::Array),
+        DataType::JSON => Ok(CastType::Json),
+        DataType::Custom(name, _) if name.to_string().eq_ignore_ascii_case("OBJECT") => Ok(CastType::Object),
+        DataType::Custom(name, _) if name.to_string().eq_ignore_ascii_case("ARRAY") => Ok(CastType::Array),
+
+        _ => Err(format!("Unsupported cast type: {}", data_type)),
+    }
+}
+
+impl CastType {
+    pub fn to_schema(&self) -> Option<SchemaNode> {
+        match self {
+            CastType::Bool => Some(SchemaNode::Bool),
+            CastType::Integer | CastType::Number => Some(SchemaNode::Number),
+            CastType::String => Some(SchemaNode::String),
+            CastType::Array => Some(SchemaNode::Array(None)),
+            CastType::Object => Some(SchemaNode::Object(None)),
+            CastType::Json | CastType::Timestamp | CastType::Interval => None,
+        }
+    }
+}
+
+/// Converts a value between JSON types, NULL and MISSING are returned unchanged.
+///
+/// | from \ to | Bool              | Number / Integer      | String    | Array / Object / Json |
+/// |-----------|-------------------|-----------------------|-----------|-----------------------|
+/// | Bool      | =                 | 1 or 0                | text      | Json only             |
+/// | Number    | not zero          | = (Integer truncates) | text      | Json only             |
+/// | String    | true/false, t/f,  | parsed number         | =         | parsed JSON text      |
+/// |           | yes/no, 1/0       |                       |           |                       |
+/// | Array     | error             | error                 | JSON text | Array or Json         |
+/// | Object    | error             | error                 | JSON text | Object or Json        |
+///
+/// Timestamps are read from ISO-8601 strings or epoch milliseconds and cast to
+/// epoch milliseconds or RFC 3339 text. Intervals are read from strings such as
+/// `5 minutes` and cast to the same text, other casts use that text.
+pub fn cast(value: &SQLValue, target: CastType) -> Result<SQLValue, String> {
+    use serde_json::Value;
+
+    if value.is_unknown() {
+        return Ok(value.clone());
+    }
+
+    match (value, target) {
+        (_, CastType::Timestamp) => return Ok(SQLValue::Timestamp(time::to_timestamp(value)?)),
+        (SQLValue::Interval(_), CastType::Interval) => return Ok(value.clone()),
+        (SQLValue::Json(Value::String(s)), CastType::Interval) => return Ok(SQLValue::Interval(time::parse_interval(s)?)),
+        (_, CastType::Interval) => return Err(format!("Cannot cast {} to Interval", value.clone().into_json())),
+        (SQLValue::Timestamp(ts), CastType::Number) => return Ok(SQLValue::from(Value::Number(time::epoch_millis(ts).to_number()?))),
+        (SQLValue::Timestamp(ts), CastType::Integer) => return Ok(SQLValue::from(Value::Number(to_integer(time::epoch_millis(ts))?))),
+        (SQLValue::Timestamp(_) | SQLValue::Interval(_), _) => return cast(&SQLValue::from(value.clone().into_json()), target),
+        _ => {}
+    }
+
+    let json = value.as_json().unwrap();
+
+    let res = match (json, target) {
+        (Value::String(s), CastType::String) => Value::String(s.clone()),
+        (_, CastType::String) => Value::String(json.to_string()),
+
+        (Value::Bool(b), CastType::Bool) => Value::Bool(*b),
+        (Value::Number(n), CastType::Bool) => Value::Bool(!JsonNumber::from(n).is_zero()),
+        (Value::String(s), CastType::Bool) => Value::Bool(parse_bool(s)?),
+
+        (Value::Bool(b), CastType::Number | CastType::Integer) => Value::from(*b as u64),
+        (Value::Number(n), CastType::Number) => Value::Number(n.clone()),
+        (Value::Number(n), CastType::Integer) => Value::Number(to_integer(JsonNumber::from(n))?),
+        (Value::String(s), CastType::Number) => Value::Number(parse_number(s)?),
+        (Value::String(s), CastType::Integer) => Value::Number(to_integer(JsonNumber::from(&parse_number(s)?))?),
+
+        (Value::String(s), CastType::Array | CastType::Object | CastType::Json) => {
+            let parsed = serde_json::from_str::<Value>(s).map_err(|e| format!("Cannot cast {:?} to {:?}: {}", s, target, e))?;
+            match (&parsed, target) {
+                (Value::Array(_), CastType::Array) | (Value::Object(_), CastType::Object) | (_, CastType::Json) => parsed,
+                _ => return Err(format!("Cannot cast {:?} to {:?}", s, target)),
+            }
+        },
+        (Value::Array(_), CastType::Array) => json.clone(),
+        (Value::Object(_), CastType::Object) => json.clone(),
+        (_, CastType::Json) => json.clone(),
+
+        _ => return Err(format!("Cannot cast {} to {:?}", json, target)),
+    };
+
+    Ok(SQLValue::from(res))
+}
+
+fn parse_bool(s: &str) -> Result<bool, String> {
+    match s.trim().to_lowercase().as_str() {
+        "true" | "t" | "yes" | "y" | "1" => Ok(true),
+        "false" | "f" | "no" | "n" | "0" => Ok(false),
+        _ => Err(format!("Cannot cast {:?} to Bool", s)),
+    }
+}
+
+fn parse_number(s: &str) -> Result<serde_json::Number, String> {
+    s.trim().parse::<serde_json::Number>().map_err(|_| format!("Cannot cast {:?} to Number", s))
+}
+
+/// Truncates toward zero
+fn to_integer(n: JsonNumber) -> Result<serde_json::Number, String> {
+    match n {
+        JsonNumber::F64(f) => match JsonNumber::F64(f.trunc()).to_integer() {
+            JsonNumber::F64(_) => Err(format!("Cannot cast {} to Integer, out of range", f)),
+            integer => integer.to_number(),
+        },
+        integer => integer.to_number(),
+    }
+}
@@ -0,0 +1,63 @@
+use std::sync::Arc;
+
+use super::execute;
+use super::parsing::{parse, parse_statement};
+use super::topic::topic_levels;
+use super::types::{BuiltQuery, ExecutionContext, QueryResult, SQLValue};
+
+/// A query parsed and built once, to run on any number of messages. Running it
+/// only reads it, so it can be shared between threads, each with the
+/// `ExecutionContext` it got from `new_context`.
+#[derive(Debug)]
+pub struct CompiledQuery {
+    query: Arc<BuiltQuery>,
+}
+
+impl CompiledQuery {
+    pub fn new(query: BuiltQuery) -> Self {
+        CompiledQuery { query: Arc::new(query) }
+    }
+
+    pub fn query(&self) -> &BuiltQuery {
+        &self.query
+    }
+
+    /// Context to run the query with, kept and reused for every message
+    pub fn new_context(&self) -> ExecutionContext {
+        self.query.new_context()
+    }
+
+    /// Values the FROM topic captures from `topic`, an error when it doesn't match
+    pub fn captures(&self, topic: &str) -> Result<SQLValue, String> {
+        let filter = self.query.from().ok_or("Query has no FROM topic".to_string())?;
+        filter.capture(&topic_levels(topic)?)
+            .map(|x| SQLValue::from(serde_json::Value::Object(x)))
+            .ok_or(format!("Topic {} doesn't match {}", topic, filter))
+    }
+
+    /// Runs the query on one message, topic captures are MISSING
+    pub fn execute(&self, context: &mut ExecutionContext, data: &SQLValue) -> Result<QueryResult, String> {
+        self.execute_with(context, SQLValue::Missing, data)
+    }
+
+    /// Runs the query on one message published on `topic`
+    pub fn execute_on(&self, context: &mut ExecutionContext, topic: &str, data: &SQLValue) -> Result<QueryResult, String> {
+        self.execute_with(context, self.captures(topic)?, data)
+    }
+
+    fn execute_with(&self, context: &mut ExecutionContext, captures: SQLValue, data: &SQLValue) -> Result<QueryResult, String> {
+        if !Arc::ptr_eq(&context.query, &self.query) {
+            return Err("Execution context was made for another query".to_string());
+        }
+        context.set_topic(captures);
+        execute::execute_query(&self.query, context, data)
+    }
+}
+
+/// Parses and builds every statement
+pub fn compile(sql_statement: String) -> Result<Vec<CompiledQuery>, String> {
+    parse(sql_statement)?
+        .into_iter()
+        .map(|(stmt, clauses)| parse_statement(stmt, clauses).map(CompiledQuery::new))
+        .collect()
+}
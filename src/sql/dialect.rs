@@ -0,0 +1,452 @@
+use sqlparser::{ast::{BinaryOperator, Distinct, Expr, Function, FunctionArg, FunctionArgExpr, Ident, Interval, JsonOperator, ObjectName, Offset, OrderByExpr, Statement, UnaryOperator, Value}, dialect::{CustomDialect, Dialect}, keywords::Keyword, parser::{Parser, ParserError}, tokenizer::{Location, Token, TokenWithLocation}};
+
+/// Wraps `CustomDialect` to add the syntax distil needs on top of it,
+/// everything else is forwarded unaltered.
+#[derive(Debug)]
+pub struct DistilDialect(pub CustomDialect);
+
+impl DistilDialect {
+    pub fn new() -> Self {
+        DistilDialect(CustomDialect {})
+    }
+}
+
+fn is_word(token: &Token, value: &str) -> bool {
+    match token {
+        Token::Word(word) => word.value.eq_ignore_ascii_case(value),
+        _ => false,
+    }
+}
+
+fn is_keyword(token: &Token, keyword: Keyword) -> bool {
+    match token {
+        Token::Word(word) => word.keyword == keyword,
+        _ => false,
+    }
+}
+
+pub fn function_expr(name: &str, args: Vec<Expr>) -> Expr {
+    Expr::Function(Function {
+        name: ObjectName(vec![Ident::new(name)]),
+        args: args.into_iter().map(|x| FunctionArg::Unnamed(FunctionArgExpr::Expr(x))).collect(),
+        filter: None,
+        null_treatment: None,
+        over: None,
+        distinct: false,
+        special: false,
+        order_by: vec![],
+    })
+}
+
+/// `expr IS [NOT] MISSING`, parsed into the `IS_MISSING` function
+fn parse_is_missing(parser: &mut Parser, expr: &Expr) -> Option<Result<Expr, ParserError>> {
+    if !is_keyword(&parser.peek_nth_token(0).token, Keyword::IS) {
+        return None;
+    }
+
+    let negated = is_keyword(&parser.peek_nth_token(1).token, Keyword::NOT);
+    let missing_position = if negated { 2 } else { 1 };
+    if !is_word(&parser.peek_nth_token(missing_position).token, "MISSING") {
+        return None;
+    }
+
+    (0..=missing_position).for_each(|_| { parser.next_token(); });
+
+    let is_missing = function_expr("IS_MISSING", vec![expr.clone()]);
+    if negated {
+        Some(Ok(Expr::UnaryOp { op: UnaryOperator::Not, expr: Box::new(is_missing) }))
+    } else {
+        Some(Ok(is_missing))
+    }
+}
+
+/// Parts of a FOREACH that sqlparser's FOREACH statement has no room for
+#[derive(Debug, Clone, Default)]
+pub struct ForeachClauses {
+    pub distinct: Option<Distinct>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    pub order_by: Vec<OrderByExpr>,
+    pub limit: Option<Expr>,
+    pub offset: Option<Offset>,
+}
+
+/// Clauses that may follow the WHERE of a FOREACH, where sqlparser's FOREACH
+/// statement ends, with the DISTINCT of its RETURN items
+pub fn parse_foreach_clauses(parser: &mut Parser, distinct: Option<Distinct>) -> Result<ForeachClauses, ParserError> {
+    let mut clauses = ForeachClauses { distinct, ..Default::default() };
+    if parser.parse_keywords(&[Keyword::GROUP, Keyword::BY]) {
+        clauses.group_by = parser.parse_comma_separated(Parser::parse_expr)?;
+    }
+    if parser.parse_keyword(Keyword::HAVING) {
+        clauses.having = Some(parser.parse_expr()?);
+    }
+    if parser.parse_keywords(&[Keyword::ORDER, Keyword::BY]) {
+        clauses.order_by = parser.parse_comma_separated(Parser::parse_order_by_expr)?;
+    }
+    // LIMIT and OFFSET in either order, like sqlparser takes them after a query
+    for _ in 0..2 {
+        if clauses.limit.is_none() && parser.parse_keyword(Keyword::LIMIT) {
+            clauses.limit = parser.parse_limit()?;
+        }
+        if clauses.offset.is_none() && parser.parse_keyword(Keyword::OFFSET) {
+            clauses.offset = Some(parser.parse_offset()?);
+        }
+    }
+    Ok(clauses)
+}
+
+/// Drops the DISTINCT or ALL right after the RETURN of a FOREACH before parsing,
+/// as sqlparser's FOREACH statement takes neither. Returns the locations of the
+/// FOREACH keywords whose RETURN is DISTINCT. `DISTINCT ON` is left for the
+/// parser to report.
+pub fn rewrite_return_distinct(tokens: Vec<TokenWithLocation>) -> (Vec<TokenWithLocation>, Vec<Location>) {
+    let significant = |x: &&TokenWithLocation| !matches!(x.token, Token::Whitespace(_));
+    let is_keyword = |token: Option<&TokenWithLocation>, keyword: Keyword| {
+        matches!(token, Some(TokenWithLocation { token: Token::Word(word), .. }) if word.keyword == keyword)
+    };
+
+    let mut rewritten = Vec::with_capacity(tokens.len());
+    let mut distinct = Vec::new();
+    let mut foreach = None;
+    for (i, token) in tokens.iter().enumerate() {
+        let after_return = is_keyword(tokens[..i].iter().rev().find(significant), Keyword::RETURN);
+        let before_on = is_keyword(tokens[i + 1..].iter().find(significant), Keyword::ON);
+        match &token.token {
+            word if is_word(word, "FOREACH") => foreach = Some(token.location),
+            Token::Word(word) if after_return && word.keyword == Keyword::ALL => continue,
+            Token::Word(word) if after_return && word.keyword == Keyword::DISTINCT && !before_on => {
+                distinct.extend(foreach);
+                continue;
+            },
+            _ => {},
+        }
+        rewritten.push(token.clone());
+    }
+    (rewritten, distinct)
+}
+
+/// Binds subscripts and field access as tightly as sqlparser binds `[`
+const ACCESS_PRECEDENCE: u8 = 50;
+
+/// Subscript identifier of the `[*]` wildcard, matching every element or member
+pub const WILDCARD_KEY: &str = "*";
+
+/// Prefix of the subscript identifier of `..key`, matching the key at any depth
+pub const DESCENDANT_PREFIX: &str = "..";
+
+/// Rewrites `.*` to `[*]` and `..key` to a `[..key]` subscript before parsing.
+/// sqlparser reads a `.` after an identifier as part of a compound identifier or
+/// qualified wildcard before the dialect is asked, so both are turned into
+/// subscripts. The `..key` word can't come from the tokenizer unquoted.
+pub fn rewrite_path_steps(tokens: Vec<TokenWithLocation>) -> Vec<TokenWithLocation> {
+    let mut rewritten = Vec::with_capacity(tokens.len());
+    let mut tokens = tokens.into_iter().peekable();
+
+    while let Some(token) = tokens.next() {
+        if token.token != Token::Period {
+            rewritten.push(token);
+            continue;
+        }
+
+        let location = token.location;
+        let with_location = |token: Token| TokenWithLocation { token, location };
+        match tokens.peek().map(|x| &x.token) {
+            Some(Token::Mul) => {
+                tokens.next();
+                rewritten.extend([Token::LBracket, Token::Mul, Token::RBracket].map(with_location));
+            },
+            Some(Token::Period) => {
+                tokens.next();
+                match tokens.next() {
+                    Some(TokenWithLocation { token: Token::Word(word), .. }) => {
+                        let key = Token::make_word(&format!("{}{}", DESCENDANT_PREFIX, word.value), None);
+                        rewritten.extend([Token::LBracket, key, Token::RBracket].map(with_location));
+                    },
+                    // Left for the parser to report
+                    other => rewritten.extend([Some(token), Some(with_location(Token::Period)), other].into_iter().flatten()),
+                }
+            },
+            _ => rewritten.push(token),
+        }
+    }
+
+    rewritten
+}
+
+/// Precedence sqlparser gives to unary plus and minus
+const UNARY_PRECEDENCE: u8 = 30;
+
+/// `~expr`, sqlparser only parses bitwise NOT for PostgreSQL
+fn parse_bitwise_not(parser: &mut Parser) -> Option<Result<Expr, ParserError>> {
+    if !parser.consume_token(&Token::Tilde) {
+        return None;
+    }
+
+    Some(parser.parse_subexpr(UNARY_PRECEDENCE).map(|expr| Expr::UnaryOp { op: UnaryOperator::PGBitwiseNot, expr: Box::new(expr) }))
+}
+
+/// `expr << n` and `expr >> n`, which sqlparser only parses for some dialects
+fn parse_shift(parser: &mut Parser, expr: &Expr, precedence: u8) -> Option<Result<Expr, ParserError>> {
+    let op = match parser.peek_token().token {
+        Token::ShiftLeft => BinaryOperator::PGBitwiseShiftLeft,
+        Token::ShiftRight => BinaryOperator::PGBitwiseShiftRight,
+        _ => return None,
+    };
+    parser.next_token();
+
+    Some(parser.parse_subexpr(precedence).map(|right| Expr::BinaryOp { left: Box::new(expr.clone()), op, right: Box::new(right) }))
+}
+
+/// Units that may follow the value of an interval, as in `INTERVAL '5' MINUTE`
+const INTERVAL_FIELDS: &[Keyword] = &[
+    Keyword::YEAR, Keyword::QUARTER, Keyword::MONTH, Keyword::WEEK, Keyword::DAY,
+    Keyword::HOUR, Keyword::MINUTE, Keyword::SECOND,
+    Keyword::MILLISECOND, Keyword::MILLISECONDS, Keyword::MICROSECOND, Keyword::MICROSECONDS,
+];
+
+/// `INTERVAL '5 minutes'` or `INTERVAL '5' MINUTE`. sqlparser reads anything up
+/// to AND or OR as the interval value, which would swallow `INTERVAL '1 day' > x`.
+fn parse_interval_literal(parser: &mut Parser) -> Option<Result<Expr, ParserError>> {
+    if !is_keyword(&parser.peek_nth_token(0).token, Keyword::INTERVAL) {
+        return None;
+    }
+
+    let value = match parser.peek_nth_token(1).token {
+        Token::SingleQuotedString(value) => Value::SingleQuotedString(value),
+        Token::Number(value, long) => Value::Number(value, long),
+        _ => return None,
+    };
+    (0..2).for_each(|_| { parser.next_token(); });
+
+    let leading_field = match parser.peek_token().token {
+        Token::Word(word) if INTERVAL_FIELDS.contains(&word.keyword) => match parser.parse_date_time_field() {
+            Ok(field) => Some(field),
+            Err(err) => return Some(Err(err)),
+        },
+        _ => None,
+    };
+
+    Some(Ok(Expr::Interval(Interval {
+        value: Box::new(Expr::Value(value)),
+        leading_field,
+        leading_precision: None,
+        last_field: None,
+        fractional_seconds_precision: None,
+    })))
+}
+
+/// `{'key': value, ...}` object literal, parsed into `OBJECT_CONSTRUCT` with its
+/// keys and values alternating. Keys are string literals or identifiers so that
+/// JSON style `{"key": value}` reads as expected.
+fn parse_object_literal(parser: &mut Parser) -> Option<Result<Expr, ParserError>> {
+    if !parser.consume_token(&Token::LBrace) {
+        return None;
+    }
+
+    Some(parse_object_members(parser).map(|args| function_expr("OBJECT_CONSTRUCT", args)))
+}
+
+fn parse_object_members(parser: &mut Parser) -> Result<Vec<Expr>, ParserError> {
+    let mut args = Vec::<Expr>::new();
+    if parser.consume_token(&Token::RBrace) {
+        return Ok(args);
+    }
+
+    loop {
+        let key_token = parser.next_token();
+        let key = match key_token.token {
+            Token::SingleQuotedString(key) => key,
+            Token::Word(word) => word.value,
+            _ => return parser.expected("an object key", key_token),
+        };
+        parser.expect_token(&Token::Colon)?;
+
+        args.push(Expr::Value(Value::SingleQuotedString(key)));
+        args.push(parser.parse_expr()?);
+
+        if !parser.consume_token(&Token::Comma) {
+            parser.expect_token(&Token::RBrace)?;
+            return Ok(args);
+        }
+    }
+}
+
+/// `expr[index]`, `expr[start:end]`, `expr[*]` and `expr.key` following a subscript or
+/// any other expression. An index becomes `ArrayIndex`, a slice becomes an `ArrayIndex`
+/// holding `start:end` as a colon `JsonAccess` with NULL for an omitted bound, a wildcard
+/// an `ArrayIndex` holding the `WILDCARD_KEY` identifier and a key a `CompositeAccess`.
+fn parse_access(parser: &mut Parser, expr: &Expr) -> Option<Result<Expr, ParserError>> {
+    if parser.consume_token(&Token::Period) {
+        return Some(parser.parse_identifier().map(|key| Expr::CompositeAccess { expr: Box::new(expr.clone()), key }));
+    }
+
+    if !parser.consume_token(&Token::LBracket) {
+        return None;
+    }
+
+    Some(parse_subscript(parser).map(|index| Expr::ArrayIndex { obj: Box::new(expr.clone()), indexes: vec![index] }))
+}
+
+/// Bound of a subscript, the sign is parsed here since a negated operand
+/// would otherwise take the following `:` as an infix operator
+fn parse_subscript_bound(parser: &mut Parser) -> Result<Expr, ParserError> {
+    if parser.consume_token(&Token::Minus) {
+        let expr = parser.parse_subexpr(ACCESS_PRECEDENCE)?;
+        return Ok(Expr::UnaryOp { op: UnaryOperator::Minus, expr: Box::new(expr) });
+    }
+    parser.parse_subexpr(ACCESS_PRECEDENCE)
+}
+
+fn parse_subscript(parser: &mut Parser) -> Result<Expr, ParserError> {
+    if parser.consume_token(&Token::Mul) {
+        parser.expect_token(&Token::RBracket)?;
+        return Ok(Expr::Identifier(Ident::new(WILDCARD_KEY)));
+    }
+
+    let start = match parser.peek_token().token {
+        Token::Colon => Expr::Value(Value::Null),
+        _ => parse_subscript_bound(parser)?,
+    };
+
+    if !parser.consume_token(&Token::Colon) {
+        parser.expect_token(&Token::RBracket)?;
+        return Ok(start);
+    }
+
+    let end = match parser.peek_token().token {
+        Token::RBracket => Expr::Value(Value::Null),
+        _ => parse_subscript_bound(parser)?,
+    };
+    parser.expect_token(&Token::RBracket)?;
+
+    Ok(Expr::JsonAccess { left: Box::new(start), operator: JsonOperator::Colon, right: Box::new(end) })
+}
+
+/// Word the `**` operator is turned into, which the tokenizer can't give unquoted
+const EXPONENT_OPERATOR: &str = "**";
+
+fn is_exponent(token: &Token) -> bool {
+    match token {
+        Token::Word(word) => word.quote_style.is_none() && word.value == EXPONENT_OPERATOR,
+        _ => false,
+    }
+}
+
+/// Rewrites `**` to a single operator token before parsing. The tokenizer gives
+/// two `*`, which only make `**` when nothing separates them.
+pub fn rewrite_exponents(tokens: Vec<TokenWithLocation>) -> Vec<TokenWithLocation> {
+    let mut rewritten = Vec::with_capacity(tokens.len());
+    let mut tokens = tokens.into_iter().peekable();
+
+    while let Some(token) = tokens.next() {
+        if token.token == Token::Mul && tokens.peek().is_some_and(|x| x.token == Token::Mul) {
+            tokens.next();
+            rewritten.push(TokenWithLocation { token: Token::make_word(EXPONENT_OPERATOR, None), location: token.location });
+        } else {
+            rewritten.push(token);
+        }
+    }
+
+    rewritten
+}
+
+/// `**` binds tighter than `*` and `/` but not as tight as field access
+const EXP_PRECEDENCE: u8 = 45;
+
+/// `expr ** n`, right associative, and `expr DIV n`, which sqlparser has operators
+/// for but never parses here
+fn parse_arithmetic(parser: &mut Parser, expr: &Expr, precedence: u8) -> Option<Result<Expr, ParserError>> {
+    let token = parser.peek_token().token;
+    let (op, right_precedence) = if is_exponent(&token) {
+        (BinaryOperator::PGExp, EXP_PRECEDENCE - 1)
+    } else if is_keyword(&token, Keyword::DIV) {
+        (BinaryOperator::MyIntegerDivide, precedence)
+    } else {
+        return None;
+    };
+    parser.next_token();
+
+    Some(parser.parse_subexpr(right_precedence).map(|right| Expr::BinaryOp { left: Box::new(expr.clone()), op, right: Box::new(right) }))
+}
+
+impl Dialect for DistilDialect {
+    fn dialect(&self) -> std::any::TypeId {
+        self.0.dialect()
+    }
+
+    fn is_identifier_start(&self, ch: char) -> bool {
+        self.0.is_identifier_start(ch)
+    }
+
+    fn is_delimited_identifier_start(&self, ch: char) -> bool {
+        self.0.is_delimited_identifier_start(ch)
+    }
+
+    fn is_proper_identifier_inside_quotes(
+        &self,
+        chars: std::iter::Peekable<std::str::Chars<'_>>,
+    ) -> bool {
+        self.0.is_proper_identifier_inside_quotes(chars)
+    }
+
+    fn supports_filter_during_aggregation(&self) -> bool {
+        self.0.supports_filter_during_aggregation()
+    }
+
+    fn supports_within_after_array_aggregation(&self) -> bool {
+        self.0.supports_within_after_array_aggregation()
+    }
+
+    fn supports_group_by_expr(&self) -> bool {
+        self.0.supports_group_by_expr()
+    }
+
+    fn supports_substring_from_for_expr(&self) -> bool {
+        self.0.supports_substring_from_for_expr()
+    }
+
+    fn supports_in_empty_list(&self) -> bool {
+        self.0.supports_in_empty_list()
+    }
+
+    fn convert_type_before_value(&self) -> bool {
+        self.0.convert_type_before_value()
+    }
+
+    fn parse_prefix(&self, parser: &mut Parser) -> Option<Result<Expr, ParserError>> {
+        parse_object_literal(parser)
+            .or_else(|| parse_interval_literal(parser))
+            .or_else(|| parse_bitwise_not(parser))
+            .or_else(|| self.0.parse_prefix(parser))
+    }
+
+    fn parse_infix(
+        &self,
+        parser: &mut Parser,
+        expr: &Expr,
+        precedence: u8,
+    ) -> Option<Result<Expr, ParserError>> {
+        parse_is_missing(parser, expr)
+            .or_else(|| parse_access(parser, expr))
+            .or_else(|| parse_shift(parser, expr, precedence))
+            .or_else(|| parse_arithmetic(parser, expr, precedence))
+            .or_else(|| self.0.parse_infix(parser, expr, precedence))
+    }
+
+    fn get_next_precedence(&self, parser: &Parser) -> Option<Result<u8, ParserError>> {
+        match parser.peek_token().token {
+            Token::Period | Token::LBracket => Some(Ok(ACCESS_PRECEDENCE)),
+            ref token if is_exponent(token) => Some(Ok(EXP_PRECEDENCE)),
+            _ => self.0.get_next_precedence(parser),
+        }
+    }
+
+    fn parse_statement(&self, parser: &mut Parser) -> Option<Result<Statement, ParserError>> {
+        self.0.parse_statement(parser)
+    }
+
+    fn is_identifier_part(&self, ch: char) -> bool {
+        self.0.is_identifier_part(ch)
+    }
+}
@@ -1,91 +1,503 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use petgraph::graph::NodeIndex;
+use regex::Regex;
 use serde_json::Number;
 use sqlparser::ast::{BinaryOperator, UnaryOperator};
 
 use crate::{json_math::JsonNumber, sql::types::NestedQueryResult};
 
-use super::types::{BuiltQuery, BuiltQueryForeach, BuiltQuerySelect, QueryResult, SimpleQueryResult, TaskAction, TaskContext};
+use super::aggregate::{Accumulator, Groups};
+use super::cast;
+use super::functions;
+use super::pattern;
+use super::time;
+use super::types::{BuiltQuery, BuiltQueryForeach, BuiltQuerySelect, AccessorSegment, ExecutionContext, MatchKind, QueryResult, QueryTask, SimpleQueryResult, SQLValue, StreamOutput, TaskAction, TaskContext, ValueKey, condition_passes};
+use super::window::{LatePolicy, Window, WindowKind, WindowSpec, WindowState};
 
-pub fn get_results(query: &mut BuiltQuerySelect) -> Result<QueryResult, String> {
-    let query_select = match &query.query_select {
-        Some(select) => Ok(select),
-        None => Err("No select items".to_string())
-    }?;
-
-    let json_context = &query.json_context;
-    let result_tasks = query_select.select_items.iter().map(|x| {
+fn select_results(query: &BuiltQuerySelect, json_context: &[SQLValue]) -> Vec<(String, serde_json::Value)> {
+    let select_items = query.query_select.as_ref().map_or(&[][..], |x| &x.select_items[..]);
+    select_items.iter().map(|x| {
         let task = &query.task_graph[*x];
         let alias = task.alias.clone().unwrap_or("".to_string());
-        let value = json_context[x.index()].clone();
+        let value = json_context[x.index()].clone().into_json();
         (alias, value)
-    }).collect::<Vec<_>>();
+    }).collect::<Vec<_>>()
+}
+
+fn condition(query: &BuiltQuerySelect, json_context: &[SQLValue]) -> Result<Option<serde_json::Value>, String> {
+    match query.query_select.as_ref().and_then(|x| x.where_expr) {
+        Some(idx) => {
+            let value = json_context[idx.index()].clone().into_json();
+            if !value.is_boolean() && !value.is_null() {
+                return Err(format!("WHERE condition must evaluate to a boolean, got {}", value));
+            }
+            Ok(Some(value))
+        },
+        None => Ok(None)
+    }
+}
+
+pub fn get_results(query: &BuiltQuerySelect, json_context: &[SQLValue]) -> Result<QueryResult, String> {
+    if query.query_select.is_none() {
+        return Err("No select items".to_string());
+    }
+
+    Ok(QueryResult::Simple(SimpleQueryResult {
+        result: select_results(query, json_context),
+        cond: condition(query, json_context)?
+    }))
+}
+
+/// Runs tasks of the query in order on `message`. Errors are kept per node instead
+/// of failing straight away, so a CASE only fails when the branch it picks failed
+fn execute_tasks(query: &BuiltQuerySelect, tasks: &[(NodeIndex, QueryTask)], json_context: &mut [SQLValue], message: &SQLValue, errors: &mut HashMap<NodeIndex, String>) {
+    for (idx, task) in tasks {
+        let inputs = TaskInputs { json_context, message, root: query.root, errors, patterns: &query.patterns };
+        match execute_task(&inputs, idx, task) {
+            Ok(res) => json_context[idx.index()] = res,
+            Err(err) => { errors.insert(*idx, err); }
+        }
+    }
+}
+
+pub fn execute_query_select(query: &BuiltQuerySelect, json_context: &mut [SQLValue], data: &SQLValue) -> Result<QueryResult, String> {
+    let mut errors = HashMap::<NodeIndex, String>::new();
+    execute_tasks(query, &query.tasks, json_context, data, &mut errors);
+
+    if let Some(query_select) = &query.query_select {
+        let outputs = query_select.select_items.iter().chain(query_select.where_expr.iter()).chain(query_select.order_by.iter().map(|x| &x.expr));
+        if let Some(err) = outputs.filter_map(|x| errors.get(x)).next() {
+            return Err(err.clone());
+        }
+    }
+
+    get_results(query, json_context)
+}
+
+/// Aggregate nodes of a query with their parents and the empty state of their accumulator
+type AggregateNodes = Vec<(NodeIndex, Vec<NodeIndex>, Accumulator)>;
+
+fn aggregate_nodes(query: &BuiltQuerySelect) -> Result<AggregateNodes, String> {
+    query.aggregates.iter().map(|idx| {
+        let task = &query.task_graph[*idx];
+        match (&task.action, &task.context) {
+            (TaskAction::Aggregate { name, distinct }, Some(TaskContext::MultiParent(parents))) => {
+                Ok((*idx, parents.clone(), Accumulator::new(name, parents.len(), *distinct)?))
+            },
+            _ => Err(format!("Invalid aggregate node: {:?}", task))
+        }
+    }).collect()
+}
+
+/// What an aggregate query takes from one row
+struct AggregatedRow {
+    key: Vec<ValueKey>,
+    args: Vec<Vec<SQLValue>>,
+    /// Event time of a windowed query
+    time: Option<SQLValue>,
+}
+
+/// Runs the per row tasks of an aggregate query, `None` when the row fails the condition
+fn aggregate_row(query: &BuiltQuerySelect, json_context: &mut [SQLValue], aggregates: &AggregateNodes, row: &SQLValue) -> Result<Option<AggregatedRow>, String> {
+    let mut errors = HashMap::<NodeIndex, String>::new();
+    execute_tasks(query, &query.tasks, json_context, row, &mut errors);
+
+    let query_select = query.query_select.as_ref().ok_or("No select items".to_string())?;
+    if let Some(err) = query_select.where_expr.and_then(|x| errors.get(&x)) {
+        return Err(err.clone());
+    }
+    if !condition_passes(&condition(query, json_context)?) {
+        return Ok(None);
+    }
+
+    let inputs = TaskInputs { json_context, message: row, root: query.root, errors: &errors, patterns: &query.patterns };
+    let key = query_select.group_by.iter().map(|x| inputs.get(x).map(ValueKey::from)).collect::<Result<Vec<_>, String>>()?;
+    let args = aggregates.iter().map(|(_, parents, _)| {
+        parents.iter().map(|x| inputs.get(x).cloned()).collect::<Result<Vec<_>, String>>()
+    }).collect::<Result<Vec<_>, String>>()?;
+    let time = match &query_select.window {
+        Some(window) => Some(inputs.get(&window.time)?.clone()),
+        None => None
+    };
+
+    Ok(Some(AggregatedRow { key, args, time }))
+}
+
+fn update_group(group: &mut [Accumulator], args: &[Vec<SQLValue>]) -> Result<(), String> {
+    for (accumulator, args) in group.iter_mut().zip(args) {
+        accumulator.update(&args.iter().collect::<Vec<_>>())?;
+    }
+    Ok(())
+}
+
+/// Feeds the rows passing the condition of an aggregate query to its aggregates.
+/// `cond` is the condition of the query the rows came from.
+fn execute_query_aggregate(query: &BuiltQuerySelect, json_context: &mut [SQLValue], rows: Vec<serde_json::Value>, cond: Option<serde_json::Value>) -> Result<QueryResult, String> {
+    let aggregates = aggregate_nodes(query)?;
+    let mut groups = Groups::new(aggregates.iter().map(|x| x.2.clone()).collect());
+
+    // Without GROUP BY every row is in one group, which exists even without rows
+    if !query.is_grouped() {
+        groups.group_mut(Vec::new());
+    }
+
+    for row in rows {
+        if let Some(row) = aggregate_row(query, json_context, &aggregates, &SQLValue::from(row))? {
+            update_group(groups.group_mut(row.key), &row.args)?;
+        }
+    }
+
+    finish_groups(query, json_context, &aggregates, groups, cond)
+}
+
+/// Without GROUP BY this is the single result computed from the aggregates, with
+/// it a nested result holding one row per group in order of first appearance, each
+/// with its HAVING condition. `cond` is the condition of the result, the HAVING of
+/// the only group when there is none.
+fn finish_groups(query: &BuiltQuerySelect, json_context: &mut [SQLValue], aggregates: &AggregateNodes, groups: Groups, cond: Option<serde_json::Value>) -> Result<QueryResult, String> {
+    let having = query.query_select.as_ref().and_then(|x| x.having);
+
+    let mut results = Vec::new();
+    for group in groups.accumulators {
+        let result = finish_group(query, json_context, aggregates, &group, having);
+        results.push((result, sort_values(query, json_context)));
+    }
+
+    if !query.is_grouped() {
+        return match results.remove(0).0? {
+            QueryResult::Simple(simple) => Ok(QueryResult::Simple(SimpleQueryResult { result: simple.result, cond: cond.or(simple.cond) })),
+            nested => Ok(nested)
+        };
+    }
+
+    Ok(QueryResult::Nested(NestedQueryResult {
+        result: arrange_rows(query, results),
+        cond
+    }))
+}
+
+/// Adds a message to the windows of a windowed query. Gives the corrected results
+/// of closed windows the message was late for, or the message itself when every
+/// window it belongs to closed and it is set aside, then the result of each window
+/// the message closes.
+pub fn execute_query_window(query: &BuiltQuerySelect, json_context: &mut [SQLValue], state: &mut WindowState, data: &SQLValue) -> Result<Vec<StreamOutput>, String> {
+    let kind = match query.query_select.as_ref().and_then(|x| x.window.as_ref()) {
+        Some(window) => window.kind.clone(),
+        None => return Err("Query is not windowed".to_string())
+    };
+
+    let aggregates = aggregate_nodes(query)?;
+    let row = match aggregate_row(query, json_context, &aggregates, data)? {
+        Some(row) => row,
+        None => return Ok(Vec::new())
+    };
 
-    let conditional = match query_select.where_expr {
+    let time = time::to_timestamp(&row.time.unwrap_or(SQLValue::Missing))?.timestamp_micros();
+    let empty = aggregates.iter().map(|x| x.2.clone()).collect::<Vec<_>>();
+
+    let mut corrected = Vec::new();
+    let mut accepted = false;
+    let mut add_to = |window: Option<&mut Window>| -> Result<(), String> {
+        if let Some(window) = window {
+            update_group(window.groups.group_mut(row.key.clone()), &row.args)?;
+            if window.closed {
+                corrected.push(window.clone());
+            }
+            accepted = true;
+        }
+        Ok(())
+    };
+
+    match kind {
+        WindowKind::Session { gap } => add_to(state.session_mut(&row.key, time, gap, &empty)?)?,
+        _ => {
+            for (start, end) in kind.assign(time) {
+                add_to(state.window_mut(start, end, &empty))?;
+            }
+        }
+    }
+
+    let mut outputs = Vec::new();
+    for window in corrected {
+        outputs.push(StreamOutput::Correction(finish_window(query, json_context, &aggregates, window)?));
+    }
+    if !accepted && state.lateness.policy == LatePolicy::SideOutput {
+        outputs.push(StreamOutput::Late(data.clone().into_json()));
+    }
+    for window in state.advance(time) {
+        outputs.push(StreamOutput::Result(finish_window(query, json_context, &aggregates, window)?));
+    }
+    Ok(outputs)
+}
+
+/// Closes every open window of a windowed query, at the end of a stream
+pub fn close_query_windows(query: &BuiltQuerySelect, json_context: &mut [SQLValue], state: &mut WindowState) -> Result<Vec<StreamOutput>, String> {
+    let aggregates = aggregate_nodes(query)?;
+    state.close_all().into_iter()
+        .map(|x| finish_window(query, json_context, &aggregates, x).map(StreamOutput::Result))
+        .collect()
+}
+
+/// Sessions hold a single group and give its result alone
+fn finish_window(query: &BuiltQuerySelect, json_context: &mut [SQLValue], aggregates: &AggregateNodes, window: Window) -> Result<QueryResult, String> {
+    let is_session = matches!(query.query_select.as_ref().and_then(|x| x.window.as_ref()), Some(WindowSpec { kind: WindowKind::Session { .. }, .. }));
+    let having = query.query_select.as_ref().and_then(|x| x.having);
+
+    let groups = window.into_groups()?;
+    match &groups.accumulators[..] {
+        [group] if is_session => finish_group(query, json_context, aggregates, group, having),
+        _ => finish_groups(query, json_context, aggregates, groups, None),
+    }
+}
+
+/// Result of one group, computed from its aggregates with HAVING as its condition
+fn finish_group(query: &BuiltQuerySelect, json_context: &mut [SQLValue], aggregates: &AggregateNodes, group: &[Accumulator], having: Option<NodeIndex>) -> Result<QueryResult, String> {
+    for ((idx, _, _), accumulator) in aggregates.iter().zip(group.iter()) {
+        json_context[idx.index()] = accumulator.finish()?;
+    }
+
+    // Tasks after the aggregates only read aggregates, never the message
+    let mut errors = HashMap::<NodeIndex, String>::new();
+    execute_tasks(query, &query.final_tasks, json_context, &SQLValue::Missing, &mut errors);
+
+    if let Some(query_select) = &query.query_select {
+        let outputs = query_select.select_items.iter().chain(having.iter()).chain(query_select.order_by.iter().map(|x| &x.expr));
+        if let Some(err) = outputs.filter_map(|x| errors.get(x)).next() {
+            return Err(err.clone());
+        }
+    }
+
+    let cond = match having {
         Some(idx) => {
-            let value = json_context[idx.index()].clone();
+            let value = json_context[idx.index()].clone().into_json();
+            if !value.is_boolean() && !value.is_null() {
+                return Err(format!("HAVING condition must evaluate to a boolean, got {}", value));
+            }
             Some(value)
         },
         None => None
     };
 
     Ok(QueryResult::Simple(SimpleQueryResult {
-        result: result_tasks,
-        cond: conditional
+        result: select_results(query, json_context),
+        cond
     }))
 }
 
-pub fn execute_query_select(query: &mut BuiltQuerySelect, data: &serde_json::Value) -> Result<QueryResult, String> {
-    query.json_context[0] = data.clone();
-
-    for (idx, task) in &query.tasks {
-        match task.context.as_ref().unwrap() {
-            TaskContext::SingleParent(parent) => {
-                match &task.action {
-                    TaskAction::Accessor(ids) => {
-                        let mut parent_value = &query.json_context[parent.index()];
-                        for id in ids {
-                            parent_value = &parent_value[id];
-                        }
-                        query.json_context[idx.index()] = parent_value.clone();
-                    },
-                    TaskAction::Link => {
-                        query.json_context[idx.index()] = query.json_context[parent.index()].clone();
-                    },
-                    TaskAction::UnaryOp(op) => {
-                        let res = execute_unary_op(&query.json_context[parent.index()], op)?;
-                        query.json_context[idx.index()] = res;
-                    },
-                    _ => {}
-                }
-            },
-            TaskContext::DualParent(parent1, parent2) => {
-                let res = match &task.action {
-                    TaskAction::BinaryOp(op) => {
-                        match (&query.json_context[parent1.index()], &query.json_context[parent2.index()]) {
-                            (serde_json::Value::Number(n1), serde_json::Value::Number(n2)) => execute_binary_op_numeric((n1, n2), op),
-                            _ => Err("Other binary ops not implemented".to_string())
-                        }
-                    },
-                    _ => { Err("Other dual parent actions".to_string())}
-                }?;
-
-                query.json_context[idx.index()] = res;
+/// Values of the ORDER BY keys of the row just executed
+fn sort_values(query: &BuiltQuerySelect, json_context: &[SQLValue]) -> Vec<SQLValue> {
+    let order_by = query.query_select.as_ref().map_or(&[][..], |x| &x.order_by[..]);
+    order_by.iter().map(|x| json_context[x.expr.index()].clone()).collect()
+}
 
-            },
-            TaskContext::MultiParent(_parents) => {
+/// Applies DISTINCT, ORDER BY, OFFSET and LIMIT to the rows of a FOREACH, given
+/// with their sort values. Rows failing their condition are dropped first so they
+/// don't count towards LIMIT, rows that failed keep their order after all the
+/// others. Without any of these clauses the rows are returned as they are.
+fn arrange_rows(query: &BuiltQuerySelect, rows: Vec<(Result<QueryResult, String>, Vec<SQLValue>)>) -> Vec<Result<QueryResult, String>> {
+    let query_select = match &query.query_select {
+        Some(query_select) if query_select.distinct || !query_select.order_by.is_empty() || query_select.limit.is_some() || query_select.offset > 0 => query_select,
+        _ => return rows.into_iter().map(|x| x.0).collect()
+    };
+
+    let mut rows = rows.into_iter().filter(|(row, _)| match row {
+        Ok(QueryResult::Simple(simple)) => simple.passed(),
+        _ => true
+    }).collect::<Vec<_>>();
+
+    if query_select.distinct {
+        let mut seen = HashSet::new();
+        rows.retain(|(row, _)| match row_key(row) {
+            Some(key) => seen.insert(key),
+            None => true
+        });
+    }
+
+    // Stable, so rows with equal keys stay in array order
+    rows.sort_by(|(a, a_values), (b, b_values)| match (a.is_ok(), b.is_ok()) {
+        (true, true) => query_select.order_by.iter().zip(a_values.iter().zip(b_values))
+            .map(|(key, (a, b))| key.compare(a, b))
+            .find(|x| *x != Ordering::Equal)
+            .unwrap_or(Ordering::Equal),
+        (a_ok, b_ok) => b_ok.cmp(&a_ok)
+    });
+
+    rows.into_iter()
+        .skip(query_select.offset)
+        .take(query_select.limit.unwrap_or(usize::MAX))
+        .map(|x| x.0)
+        .collect()
+}
+
+/// Key DISTINCT compares a row by, `None` for rows that are never the same as another
+fn row_key(row: &Result<QueryResult, String>) -> Option<Vec<ValueKey>> {
+    match row {
+        Ok(QueryResult::Simple(simple)) => Some(simple.result.iter().map(|x| ValueKey::from(&x.1)).collect()),
+        _ => None
+    }
+}
+
+/// Everything a task reads: the message at the root node, values and errors of the
+/// other nodes and patterns compiled with the query
+struct TaskInputs<'a> {
+    json_context: &'a [SQLValue],
+    message: &'a SQLValue,
+    root: NodeIndex,
+    errors: &'a HashMap<NodeIndex, String>,
+    patterns: &'a HashMap<NodeIndex, Regex>,
+}
+
+impl<'a> TaskInputs<'a> {
+    fn get(&self, idx: &NodeIndex) -> Result<&'a SQLValue, String> {
+        match self.errors.get(idx) {
+            Some(err) => Err(err.clone()),
+            None if *idx == self.root => Ok(self.message),
+            None => Ok(&self.json_context[idx.index()])
+        }
+    }
+}
+
+fn execute_task(inputs: &TaskInputs, idx: &NodeIndex, task: &QueryTask) -> Result<SQLValue, String> {
+    match task.context.as_ref().unwrap() {
+        TaskContext::SingleParent(parent) => {
+            let parent = inputs.get(parent)?;
+            match &task.action {
+                TaskAction::Accessor(ids) => Ok(execute_accessor(parent, ids)),
+                TaskAction::Link => Ok(parent.clone()),
+                TaskAction::UnaryOp(op) => execute_unary_op(parent, op),
+                TaskAction::Cast { target, try_cast } => {
+                    // TRY_CAST only absorbs conversion errors, errors of its input still fail
+                    match (cast::cast(parent, *target), try_cast) {
+                        (Err(_), true) => Ok(SQLValue::from(serde_json::Value::Null)),
+                        (res, _) => res
+                    }
+                },
+                _ => Err("Other single parent actions".to_string())
             }
+        },
+        TaskContext::DualParent(parent1, parent2) => {
+            match &task.action {
+                TaskAction::BinaryOp(op) => execute_binary_op((inputs.get(parent1)?, inputs.get(parent2)?), op),
+                TaskAction::Match { kind, negated } => {
+                    execute_match((inputs.get(parent1)?, inputs.get(parent2)?), kind, *negated, inputs.patterns.get(idx))
+                },
+                _ => Err("Other dual parent actions".to_string())
+            }
+        },
+        TaskContext::MultiParent(parents) => {
+            match &task.action {
+                TaskAction::Function { name, def } => {
+                    let args = parents.iter().map(|x| inputs.get(x)).collect::<Result<Vec<_>, String>>()?;
+                    functions::call(def, name, &args, inputs.patterns.get(idx))
+                },
+                TaskAction::Case { has_operand, has_else } => execute_case(inputs, parents, *has_operand, *has_else),
+                TaskAction::InList { negated } => {
+                    let args = parents.iter().map(|x| inputs.get(x)).collect::<Result<Vec<_>, String>>()?;
+                    execute_in_list(args[0], &args[1..], *negated)
+                },
+                TaskAction::Between { negated } => {
+                    let args = parents.iter().map(|x| inputs.get(x)).collect::<Result<Vec<_>, String>>()?;
+                    execute_between(args[0], args[1], args[2], *negated)
+                },
+                _ => Err("Other multi parent actions".to_string())
+            }
+        }
+    }
+}
+
+/// Matches a string against a pattern, using the compiled pattern when there is one
+fn execute_match(parameters: (&SQLValue, &SQLValue), kind: &MatchKind, negated: bool, compiled: Option<&Regex>) -> Result<SQLValue, String> {
+    if let Some(unknown) = SQLValue::unknown_of(&[parameters.0, parameters.1]) {
+        return Ok(unknown);
+    }
+
+    let (value, pattern) = match (parameters.0.as_json(), parameters.1.as_json()) {
+        (Some(serde_json::Value::String(value)), Some(serde_json::Value::String(pattern))) => (value, pattern),
+        _ => return Err(format!("Pattern matching requires strings, got {} and {}", parameters.0.clone().into_json(), parameters.1.clone().into_json()))
+    };
+
+    let matched = match compiled {
+        Some(regex) => regex.is_match(value),
+        None => pattern::compile(kind, pattern)?.is_match(value)
+    };
+
+    Ok(SQLValue::from(serde_json::Value::Bool(matched != negated)))
+}
+
+/// `x IN (a, b)` is `x = a OR x = b`, so NULL and MISSING follow `=` and OR
+fn execute_in_list(value: &SQLValue, list: &[&SQLValue], negated: bool) -> Result<SQLValue, String> {
+    let mut res = SQLValue::from(serde_json::Value::Bool(false));
+    for item in list {
+        let eq = execute_binary_op((value, item), &BinaryOperator::Eq)?;
+        res = execute_binary_op((&res, &eq), &BinaryOperator::Or)?;
+        if res.as_json() == Some(&serde_json::Value::Bool(true)) {
+            break;
+        }
+    }
+
+    match negated {
+        true => execute_unary_op(&res, &UnaryOperator::Not),
+        false => Ok(res)
+    }
+}
+
+/// `x BETWEEN low AND high` is `x >= low AND x <= high`
+fn execute_between(value: &SQLValue, low: &SQLValue, high: &SQLValue, negated: bool) -> Result<SQLValue, String> {
+    let above = execute_binary_op((value, low), &BinaryOperator::GtEq)?;
+    let below = execute_binary_op((value, high), &BinaryOperator::LtEq)?;
+    let res = execute_binary_op((&above, &below), &BinaryOperator::And)?;
+
+    match negated {
+        true => execute_unary_op(&res, &UnaryOperator::Not),
+        false => Ok(res)
+    }
+}
+
+/// Evaluates branches in order and only reads the result of the branch taken.
+/// A searched CASE takes the first TRUE condition, a simple CASE the first value
+/// equal (`=`) to the operand. Without a match the ELSE result or NULL is returned.
+fn execute_case(inputs: &TaskInputs, parents: &[NodeIndex], has_operand: bool, has_else: bool) -> Result<SQLValue, String> {
+    let mut parents = parents.iter();
+    let operand = match has_operand {
+        true => Some(inputs.get(parents.next().unwrap())?),
+        false => None
+    };
+    let else_result = match has_else {
+        true => parents.next_back(),
+        false => None
+    };
+
+    for branch in parents.as_slice().chunks(2) {
+        let cond = inputs.get(&branch[0])?;
+        let matched = match operand {
+            Some(operand) => execute_binary_op((operand, cond), &BinaryOperator::Eq)?,
+            None => cond.clone()
+        };
+
+        if as_logical(&matched, &"CASE WHEN")? == Some(true) {
+            return inputs.get(&branch[1]).cloned();
         }
     }
 
-    get_results(query)
+    match else_result {
+        Some(idx) => inputs.get(idx).cloned(),
+        None => Ok(SQLValue::from(serde_json::Value::Null))
+    }
 }
 
-pub fn execute_query_foreach(query: &mut BuiltQueryForeach, data: &serde_json::Value) -> Result<QueryResult,String> {
-    let query_result = match execute_query_select(&mut query.main, data)? {
+pub fn execute_query_foreach(query: &BuiltQueryForeach, context: &mut ExecutionContext, data: &SQLValue) -> Result<QueryResult,String> {
+    let query_result = match execute_query_select(&query.main, &mut context.main, data)? {
         QueryResult::Simple(simple) => Ok(simple),
         _ => Err("Foreach query must return simple result".to_string())
     }?;
 
-    if query_result.cond.is_some() && !query_result.cond.as_ref().unwrap().as_bool().unwrap_or(true) {
+    if !query_result.passed() {
+        if query.foreach.is_aggregate() {
+            return execute_query_aggregate(&query.foreach, &mut context.foreach, Vec::new(), query_result.cond);
+        }
+
         let result = QueryResult::Nested(NestedQueryResult {
             result: Vec::new(),
             cond: query_result.cond
@@ -100,78 +512,410 @@ pub fn execute_query_foreach(query: &mut BuiltQueryForeach, data: &serde_json::V
     
     let res = query_result.result[0].1.clone();
 
-    let mut results = Vec::new();
-    match res {
-        serde_json::Value::Array(arr) => {
-            for item in arr {
-                let res = execute_query_select(&mut query.foreach, &item);
-                results.push(res);
-            }
-        },
+    let arr = match res {
+        serde_json::Value::Array(arr) => arr,
         _ => {
             return Err("Foreach query must return array".to_string());
         }
+    };
+
+    if query.foreach.is_aggregate() {
+        return execute_query_aggregate(&query.foreach, &mut context.foreach, arr, query_result.cond);
+    }
+
+    let mut results = Vec::new();
+    for item in arr {
+        let res = execute_query_select(&query.foreach, &mut context.foreach, &SQLValue::from(item));
+        results.push((res, sort_values(&query.foreach, &context.foreach)));
     }
 
     Ok(QueryResult::Nested(NestedQueryResult {
-        result: results,
+        result: arrange_rows(&query.foreach, results),
         cond: query_result.cond
     }))
 }
 
-pub fn execute_query(query: &mut BuiltQuery, data: &serde_json::Value) -> Result<QueryResult, String> {
+/// Runs a query on one message, `context` must have been made for this query
+pub fn execute_query(query: &BuiltQuery, context: &mut ExecutionContext, data: &SQLValue) -> Result<QueryResult, String> {
     match query {
-        BuiltQuery::SELECT(select) => execute_query_select(select, data),
-        BuiltQuery::FOREACH(foreach) => execute_query_foreach(foreach, data)
+        BuiltQuery::SELECT(select) if select.is_windowed() => Err("Windowed queries only run on a stream of messages".to_string()),
+        BuiltQuery::SELECT(select) => execute_query_select(select, &mut context.main, data),
+        BuiltQuery::FOREACH(foreach) => execute_query_foreach(foreach, context, data)
     }
 }
 
-fn execute_unary_op(param : &serde_json::Value, op : &UnaryOperator) -> Result<serde_json::Value, String> {
+/// Walks object keys, an absent key or a key on a non object is MISSING and a key on NULL is NULL
+fn execute_accessor(parent : &SQLValue, ids : &[AccessorSegment]) -> SQLValue {
+    if ids.iter().any(|x| x.is_multi()) {
+        return execute_path_query(parent, ids);
+    }
+
+    let mut value = match parent.as_json() {
+        Some(value) => value,
+        None => return SQLValue::Missing
+    };
+
+    for (i, id) in ids.iter().enumerate() {
+        value = match (value, id) {
+            (serde_json::Value::Null, _) => return SQLValue::from(serde_json::Value::Null),
+            (serde_json::Value::Object(map), AccessorSegment::Key(key)) => match map.get(key) {
+                Some(child) => child,
+                None => return SQLValue::Missing
+            },
+            (serde_json::Value::Array(arr), AccessorSegment::Index(index)) => {
+                match array_position(arr.len(), *index).and_then(|x| arr.get(x)) {
+                    Some(child) => child,
+                    None => return SQLValue::Missing
+                }
+            },
+            (serde_json::Value::Array(arr), AccessorSegment::Slice(start, end)) => {
+                let sliced = SQLValue::from(serde_json::Value::Array(arr[slice_range(arr.len(), *start, *end)].to_vec()));
+                return execute_accessor(&sliced, &ids[i + 1..]);
+            },
+            _ => return SQLValue::Missing
+        };
+    }
+
+    SQLValue::from(value.clone())
+}
+
+/// Paths with a wildcard or recursive step select every match JSONPath style and
+/// return them as an array in document order, empty when nothing matches. Every step,
+/// slices included, selects nodes and steps that don't apply to a node select nothing.
+fn execute_path_query(parent : &SQLValue, ids : &[AccessorSegment]) -> SQLValue {
+    let mut nodes = match parent.as_json() {
+        Some(serde_json::Value::Null) => return parent.clone(),
+        Some(value) => vec![value],
+        None => return SQLValue::Missing
+    };
+
+    for id in ids {
+        nodes = nodes.into_iter().flat_map(|node| select_children(node, id)).collect();
+    }
+
+    SQLValue::from(serde_json::Value::Array(nodes.into_iter().cloned().collect()))
+}
+
+fn select_children<'a>(node : &'a serde_json::Value, id : &AccessorSegment) -> Vec<&'a serde_json::Value> {
+    match (node, id) {
+        (serde_json::Value::Object(map), AccessorSegment::Key(key)) => map.get(key).into_iter().collect(),
+        (serde_json::Value::Array(arr), AccessorSegment::Index(index)) => {
+            array_position(arr.len(), *index).and_then(|x| arr.get(x)).into_iter().collect()
+        },
+        (serde_json::Value::Array(arr), AccessorSegment::Slice(start, end)) => {
+            arr[slice_range(arr.len(), *start, *end)].iter().collect()
+        },
+        (serde_json::Value::Object(map), AccessorSegment::Wildcard) => map.values().collect(),
+        (serde_json::Value::Array(arr), AccessorSegment::Wildcard) => arr.iter().collect(),
+        (_, AccessorSegment::Descendant(key)) => {
+            let mut found = Vec::new();
+            collect_descendants(node, key, &mut found);
+            found
+        },
+        _ => vec![]
+    }
+}
+
+/// Values of `key` in `node` and anything nested below it
+fn collect_descendants<'a>(node : &'a serde_json::Value, key : &str, found : &mut Vec<&'a serde_json::Value>) {
+    match node {
+        serde_json::Value::Object(map) => {
+            if let Some(value) = map.get(key) {
+                found.push(value);
+            }
+            map.values().for_each(|child| collect_descendants(child, key, found));
+        },
+        serde_json::Value::Array(arr) => arr.iter().for_each(|child| collect_descendants(child, key, found)),
+        _ => {}
+    }
+}
+
+/// Range of a slice over `len` elements, bounds are clamped to the array
+fn slice_range(len: usize, start: Option<i64>, end: Option<i64>) -> std::ops::Range<usize> {
+    let bound = |x: i64| array_position(len, x).unwrap_or(0).min(len);
+    let start = start.map_or(0, bound);
+    let end = end.map_or(len, bound).max(start);
+    start..end
+}
+
+/// Position of an index in an array of `len` elements, negative indexes count
+/// from the end and `None` is before the start
+fn array_position(len: usize, index: i64) -> Option<usize> {
+    if index >= 0 {
+        Some(index as usize)
+    } else {
+        len.checked_sub(index.unsigned_abs() as usize)
+    }
+}
+
+fn execute_unary_op(param : &SQLValue, op : &UnaryOperator) -> Result<SQLValue, String> {
+    if *op == UnaryOperator::Not {
+        let bool_val = as_logical(param, op)?;
+        return Ok(SQLValue::from(bool_val.map_or(serde_json::Value::Null, |b| serde_json::Value::Bool(!b))));
+    }
+
+    if let Some(unknown) = SQLValue::unknown_of(&[param]) {
+        return Ok(unknown);
+    }
+    let param = match (param, op) {
+        (SQLValue::Interval(_), UnaryOperator::Plus) => return Ok(param.clone()),
+        (SQLValue::Interval(interval), UnaryOperator::Minus) => return Ok(SQLValue::Interval(interval.checked_neg()?)),
+        (SQLValue::Json(param), _) => param,
+        _ => return Err(format!("{:?} not implemented for type {:?}", op, param))
+    };
+
     match op {
         UnaryOperator::Plus => {
-            Ok(param.clone())
+            Ok(SQLValue::from(param.clone()))
         },
         UnaryOperator::Minus => {
             match param {
-                serde_json::Value::Number(n) => if n.is_i64() {
-                    Ok(serde_json::Value::Number(serde_json::Number::from(-n.as_i64().unwrap())))
-                } else if n.is_u64() {
-                    Ok(serde_json::Value::Number(serde_json::Number::from(0-n.as_u64().unwrap())))
-                } else {
-                    Ok(serde_json::Value::Number(serde_json::Number::from_f64(-n.as_f64().unwrap()).unwrap()))
-                }
+                serde_json::Value::Number(n) => {
+                    Ok(SQLValue::from(serde_json::Value::Number(JsonNumber::from(n).checked_neg()?.to_number()?)))
+                },
+                _ => Err(format!("{:?} not implemented for type {:?}", op, param))
+            }
+        },
+        UnaryOperator::PGBitwiseNot => {
+            match param {
+                serde_json::Value::Number(n) => {
+                    Ok(SQLValue::from(serde_json::Value::Number(JsonNumber::from(n).checked_bitnot()?.to_number()?)))
+                },
                 _ => Err(format!("{:?} not implemented for type {:?}", op, param))
             }
         },
-        UnaryOperator::Not => {
-            let bool_val = param.as_bool();
+        _=> Err(format!("Unary op {:?} not implemented", op))
+    }
+}
+
+pub fn execute_binary_op(parameters : (&SQLValue, &SQLValue), op : &BinaryOperator) -> Result<SQLValue, String> {
+    if matches!(op, BinaryOperator::And | BinaryOperator::Or | BinaryOperator::Xor) {
+        return Ok(SQLValue::from(execute_binary_op_logical(parameters, op)?));
+    }
+
+    if let Some(unknown) = SQLValue::unknown_of(&[parameters.0, parameters.1]) {
+        return Ok(unknown);
+    }
+
+    match (parameters.0.as_json(), parameters.1.as_json()) {
+        (Some(p0), Some(p1)) if *op == BinaryOperator::StringConcat => Ok(SQLValue::from(execute_string_concat((p0, p1))?)),
+        (Some(p0), Some(p1)) => Ok(SQLValue::from(execute_binary_op_json((p0, p1), op)?)),
+        _ if *op == BinaryOperator::StringConcat => {
+            let (p0, p1) = (parameters.0.clone().into_json(), parameters.1.clone().into_json());
+            Ok(SQLValue::from(execute_string_concat((&p0, &p1))?))
+        },
+        _ => execute_binary_op_time(parameters, op),
+    }
+}
+
+/// Operations with a timestamp or interval, a string or number next to a timestamp
+/// or added to an interval is read as a timestamp
+fn execute_binary_op_time(parameters : (&SQLValue, &SQLValue), op : &BinaryOperator) -> Result<SQLValue, String> {
+    use serde_json::Value;
 
-            if bool_val.is_none() {
-                return Err(format!("Not operator ({}) requires boolean", op));
+    match (parameters.0, parameters.1, op) {
+        (SQLValue::Interval(i1), SQLValue::Interval(i2), BinaryOperator::Plus) => Ok(SQLValue::Interval(i1.checked_add(*i2)?)),
+        (SQLValue::Interval(i1), SQLValue::Interval(i2), BinaryOperator::Minus) => Ok(SQLValue::Interval(i1.checked_add(i2.checked_neg()?)?)),
+        (SQLValue::Interval(i1), SQLValue::Interval(i2), _) if is_comparison(op) => {
+            Ok(SQLValue::from(execute_comparison(Some(i1.approx_micros().cmp(&i2.approx_micros())), op)?))
+        },
+        (SQLValue::Interval(interval), SQLValue::Json(Value::Number(n)), BinaryOperator::Multiply)
+        | (SQLValue::Json(Value::Number(n)), SQLValue::Interval(interval), BinaryOperator::Multiply) => {
+            Ok(SQLValue::Interval(interval.checked_mul(JsonNumber::from(n))?))
+        },
+        (SQLValue::Interval(interval), SQLValue::Json(Value::Number(n)), BinaryOperator::Divide) => {
+            Ok(SQLValue::Interval(interval.checked_div(JsonNumber::from(n))?))
+        },
+        (ts, SQLValue::Interval(interval), BinaryOperator::Plus) | (SQLValue::Interval(interval), ts, BinaryOperator::Plus) => {
+            Ok(SQLValue::Timestamp(time::add_interval(&time::to_timestamp(ts)?, interval)?))
+        },
+        (ts, SQLValue::Interval(interval), BinaryOperator::Minus) => {
+            Ok(SQLValue::Timestamp(time::add_interval(&time::to_timestamp(ts)?, &interval.checked_neg()?)?))
+        },
+        (SQLValue::Interval(_), _, _) | (_, SQLValue::Interval(_), _) => {
+            Err(format!("Operation {:?} not implemented between {} and {}", op, parameters.0.clone().into_json(), parameters.1.clone().into_json()))
+        },
+        (ts1, ts2, BinaryOperator::Minus) => {
+            Ok(SQLValue::Interval(time::sub_timestamps(&time::to_timestamp(ts1)?, &time::to_timestamp(ts2)?)?))
+        },
+        (ts1, ts2, _) if is_comparison(op) => {
+            let ordering = time::to_timestamp(ts1)?.cmp(&time::to_timestamp(ts2)?);
+            Ok(SQLValue::from(execute_comparison(Some(ordering), op)?))
+        },
+        _ => Err(format!("Operation {:?} not implemented between {} and {}", op, parameters.0.clone().into_json(), parameters.1.clone().into_json()))
+    }
+}
+
+fn execute_binary_op_json(parameters : (&serde_json::Value, &serde_json::Value), op : &BinaryOperator) -> Result<serde_json::Value, String> {
+    use serde_json::Value;
+
+    match parameters {
+        (Value::Number(n1), Value::Number(n2)) => execute_binary_op_numeric((n1, n2), op),
+        (Value::String(s1), Value::String(s2)) => execute_binary_op_string((s1, s2), op),
+        (Value::String(s), Value::Number(n)) => {
+            let n1 = coerce_string_to_number(s, op)?;
+            match n1 {
+                Some(n1) => execute_binary_op_numeric((&n1, n), op),
+                None => execute_comparison(None, op)
             }
+        },
+        (Value::Number(n), Value::String(s)) => {
+            let n2 = coerce_string_to_number(s, op)?;
+            match n2 {
+                Some(n2) => execute_binary_op_numeric((n, &n2), op),
+                None => execute_comparison(None, op)
+            }
+        },
+        (Value::Bool(b1), Value::Bool(b2)) => execute_comparison(Some(b1.cmp(b2)), op),
+        (v1, v2) => {
+            // Arrays and objects only support deep equality, mismatched types are never equal
+            let ordering = if v1 == v2 { Some(Ordering::Equal) } else { None };
+            execute_comparison(ordering, op)
+        }
+    }
+}
+
+/// Comparing a string with a number compares numerically when the string holds
+/// a number, returns `None` when it does not and fails for non comparison operators.
+fn coerce_string_to_number(s : &str, op : &BinaryOperator) -> Result<Option<Number>, String> {
+    if !is_comparison(op) {
+        return Err(format!("Operation {:?} not implemented between string and number", op));
+    }
+
+    Ok(s.trim().parse::<Number>().ok())
+}
+
+fn is_comparison(op : &BinaryOperator) -> bool {
+    matches!(op,
+        BinaryOperator::Eq | BinaryOperator::NotEq |
+        BinaryOperator::Lt | BinaryOperator::LtEq |
+        BinaryOperator::Gt | BinaryOperator::GtEq
+    )
+}
 
-            Ok(serde_json::Value::Bool(
-                !bool_val.unwrap()
-            ))
+/// Maps the ordering of two operands to the result of a comparison operator,
+/// operands without an ordering are unequal and their ordering is unknown (NULL).
+fn execute_comparison(ordering : Option<Ordering>, op : &BinaryOperator) -> Result<serde_json::Value, String> {
+    let res = match (op, ordering) {
+        (BinaryOperator::Eq, ord) => Some(ord == Some(Ordering::Equal)),
+        (BinaryOperator::NotEq, ord) => Some(ord != Some(Ordering::Equal)),
+        (BinaryOperator::Lt, Some(ord)) => Some(ord == Ordering::Less),
+        (BinaryOperator::LtEq, Some(ord)) => Some(ord != Ordering::Greater),
+        (BinaryOperator::Gt, Some(ord)) => Some(ord == Ordering::Greater),
+        (BinaryOperator::GtEq, Some(ord)) => Some(ord != Ordering::Less),
+        (op, _) if is_comparison(op) => None,
+        _ => return Err(format!("Operation {:?} not implemented", op))
+    };
+
+    Ok(res.map_or(serde_json::Value::Null, serde_json::Value::Bool))
+}
+
+/// Reads a boolean operand for three-valued logic, NULL and MISSING are UNKNOWN (`None`).
+fn as_logical(value : &SQLValue, op : &dyn std::fmt::Display) -> Result<Option<bool>, String> {
+    match value.as_json() {
+        _ if value.is_unknown() => Ok(None),
+        Some(serde_json::Value::Bool(b)) => Ok(Some(*b)),
+        _ => Err(format!("Operator ({}) requires boolean, got {}", op, value.clone().into_json()))
+    }
+}
+
+fn execute_binary_op_logical(parameters : (&SQLValue, &SQLValue), op : &BinaryOperator) -> Result<serde_json::Value, String> {
+    let b1 = as_logical(parameters.0, op)?;
+    let b2 = as_logical(parameters.1, op)?;
+
+    let res = match op {
+        BinaryOperator::And => match (b1, b2) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None
         },
-        _=> Err(format!("Unary op {:?} not implemented", op))
+        BinaryOperator::Or => match (b1, b2) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None
+        },
+        BinaryOperator::Xor => match (b1, b2) {
+            (Some(b1), Some(b2)) => Some(b1 ^ b2),
+            _ => None
+        },
+        _ => return Err(format!("Operation {:?} is not a logical operator", op))
+    };
+
+    Ok(res.map_or(serde_json::Value::Null, serde_json::Value::Bool))
+}
+
+fn execute_binary_op_string(parameters : (&String, &String), op : &BinaryOperator) -> Result<serde_json::Value, String> {
+    if !is_comparison(op) {
+        return Err(format!("Operation {:?} not implemented for strings", op));
     }
+
+    execute_comparison(Some(parameters.0.cmp(parameters.1)), op)
+}
+
+fn execute_string_concat(parameters : (&serde_json::Value, &serde_json::Value)) -> Result<serde_json::Value, String> {
+    let to_text = |value : &serde_json::Value| -> Result<String, String> {
+        match value {
+            serde_json::Value::String(s) => Ok(s.clone()),
+            serde_json::Value::Number(n) => Ok(n.to_string()),
+            serde_json::Value::Bool(b) => Ok(b.to_string()),
+            _ => Err(format!("Operation {:?} not implemented for {}", BinaryOperator::StringConcat, value))
+        }
+    };
+
+    Ok(serde_json::Value::String(to_text(parameters.0)? + &to_text(parameters.1)?))
 }
 
 fn execute_binary_op_numeric(parameters : (&Number, &Number), op : &BinaryOperator) -> Result<serde_json::Value, String> {
     let n1 = parameters.0;
     let n2 = parameters.1;
 
+    let arith = |res : Result<JsonNumber, String>| -> Result<serde_json::Value, String> {
+        Ok(serde_json::Value::Number(res?.to_number()?))
+    };
+
     match op {
         BinaryOperator::Plus => {
-            Ok(serde_json::Value::from((JsonNumber::from(n1) + JsonNumber::from(n2)).to_number()))
+            arith(JsonNumber::from(n1).checked_add(JsonNumber::from(n2)))
         },
         BinaryOperator::Minus => {
-            Ok(serde_json::Value::from((JsonNumber::from(n1) - JsonNumber::from(n2)).to_number()))
+            arith(JsonNumber::from(n1).checked_sub(JsonNumber::from(n2)))
+        },
+        BinaryOperator::Multiply => {
+            arith(JsonNumber::from(n1).checked_mul(JsonNumber::from(n2)))
+        },
+        BinaryOperator::Divide => {
+            arith(JsonNumber::from(n1).checked_div(JsonNumber::from(n2)))
+        },
+        BinaryOperator::Modulo => {
+            arith(JsonNumber::from(n1).checked_rem(JsonNumber::from(n2)))
+        },
+        BinaryOperator::MyIntegerDivide | BinaryOperator::DuckIntegerDivide => {
+            arith(JsonNumber::from(n1).checked_int_div(JsonNumber::from(n2)))
+        },
+        BinaryOperator::PGExp => {
+            arith(JsonNumber::from(n1).checked_pow(JsonNumber::from(n2)))
+        },
+        BinaryOperator::BitwiseAnd => {
+            arith(JsonNumber::from(n1).checked_bitand(JsonNumber::from(n2)))
+        },
+        BinaryOperator::BitwiseOr => {
+            arith(JsonNumber::from(n1).checked_bitor(JsonNumber::from(n2)))
+        },
+        BinaryOperator::BitwiseXor => {
+            arith(JsonNumber::from(n1).checked_bitxor(JsonNumber::from(n2)))
+        },
+        BinaryOperator::PGBitwiseShiftLeft => {
+            arith(JsonNumber::from(n1).checked_shl(JsonNumber::from(n2)))
+        },
+        BinaryOperator::PGBitwiseShiftRight => {
+            arith(JsonNumber::from(n1).checked_shr(JsonNumber::from(n2)))
         },
         BinaryOperator::Eq => {
             Ok(serde_json::json!(JsonNumber::from(n1) == JsonNumber::from(n2)))
         },
+        BinaryOperator::NotEq => {
+            Ok(serde_json::json!(JsonNumber::from(n1) != JsonNumber::from(n2)))
+        },
         BinaryOperator::Lt => {
             Ok(serde_json::json!(JsonNumber::from(n1) < JsonNumber::from(n2)))
         },
@@ -0,0 +1,555 @@
+use std::cmp::Ordering;
+
+use phf::phf_map;
+use regex::Regex;
+use sqlparser::ast::BinaryOperator;
+
+use crate::json_math::JsonNumber;
+
+use super::execute;
+use super::pattern;
+use super::schema::SchemaNode;
+use super::time;
+use super::types::{MatchKind, SQLValue};
+
+/// Type accepted by a function parameter or produced by a function.
+/// `Any` is used where the type cannot be narrowed down, and a NULL passed
+/// to any other parameter type makes the function return NULL.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArgType {
+    Any,
+    Bool,
+    Number,
+    String,
+    Array,
+    Object,
+    /// Timestamp, ISO-8601 string or epoch milliseconds
+    Timestamp,
+}
+
+impl ArgType {
+    pub fn accepts(&self, value: &SQLValue) -> bool {
+        use serde_json::Value;
+
+        match (self, value) {
+            (ArgType::Any, _) => true,
+            (ArgType::Bool, SQLValue::Json(Value::Bool(_))) => true,
+            (ArgType::Number, SQLValue::Json(Value::Number(_))) => true,
+            (ArgType::String, SQLValue::Json(Value::String(_))) => true,
+            (ArgType::Array, SQLValue::Json(Value::Array(_))) => true,
+            (ArgType::Object, SQLValue::Json(Value::Object(_))) => true,
+            (ArgType::Timestamp, SQLValue::Timestamp(_) | SQLValue::Json(Value::String(_) | Value::Number(_))) => true,
+            _ => false,
+        }
+    }
+
+    /// Checks a type known while building the query against this parameter type.
+    pub fn accepts_schema(&self, schema: &SchemaNode) -> bool {
+        match (self, schema) {
+            (ArgType::Any, _) => true,
+            (_, SchemaNode::Null) => true,
+            (_, SchemaNode::Nullable(inner)) => self.accepts_schema(inner),
+            (ArgType::Bool, SchemaNode::Bool) => true,
+            (ArgType::Number, SchemaNode::Number) => true,
+            (ArgType::String, SchemaNode::String) => true,
+            (ArgType::Array, SchemaNode::Array(_)) => true,
+            (ArgType::Object, SchemaNode::Object(_)) => true,
+            (ArgType::Timestamp, SchemaNode::String | SchemaNode::Number) => true,
+            _ => false,
+        }
+    }
+
+    pub fn to_schema(&self) -> Option<SchemaNode> {
+        match self {
+            ArgType::Any => None,
+            ArgType::Bool => Some(SchemaNode::Bool),
+            ArgType::Number => Some(SchemaNode::Number),
+            ArgType::String => Some(SchemaNode::String),
+            ArgType::Array => Some(SchemaNode::Array(None)),
+            ArgType::Object => Some(SchemaNode::Object(None)),
+            ArgType::Timestamp => None,
+        }
+    }
+}
+
+pub type ScalarFn = fn(&[&SQLValue]) -> Result<SQLValue, String>;
+
+/// Functions taking a regex as their second argument get it compiled
+pub type PatternFn = fn(&[&SQLValue], &Regex) -> Result<SQLValue, String>;
+
+pub enum FunctionImpl {
+    Scalar(ScalarFn),
+    Pattern(PatternFn),
+}
+
+pub struct FunctionDef {
+    /// Positional parameter types, the last one repeats when `variadic` is set
+    pub args: &'static [ArgType],
+    pub min_args: usize,
+    pub variadic: bool,
+    /// Strict functions return NULL or MISSING without being called when any argument is
+    pub strict: bool,
+    pub returns: ArgType,
+    pub func: FunctionImpl,
+}
+
+/// Definitions are only ever equal to themselves, there is one for each name
+impl PartialEq for FunctionDef {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl PartialOrd for FunctionDef {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        (self == other).then_some(Ordering::Equal)
+    }
+}
+
+impl std::fmt::Debug for FunctionDef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FunctionDef").field("args", &self.args).field("returns", &self.returns).finish_non_exhaustive()
+    }
+}
+
+impl FunctionDef {
+    pub fn is_pattern(&self) -> bool {
+        matches!(self.func, FunctionImpl::Pattern(_))
+    }
+
+    fn arg_type(&self, position: usize) -> ArgType {
+        if position < self.args.len() {
+            self.args[position]
+        } else {
+            *self.args.last().unwrap_or(&ArgType::Any)
+        }
+    }
+
+    pub fn check_arg_count(&self, name: &str, count: usize) -> Result<(), String> {
+        if count < self.min_args {
+            return Err(format!("Function {} expects at least {} arguments, got {}", name, self.min_args, count));
+        }
+        if !self.variadic && count > self.args.len() {
+            return Err(format!("Function {} expects at most {} arguments, got {}", name, self.args.len(), count));
+        }
+        Ok(())
+    }
+
+    /// Checks argument types that are known while building the query,
+    /// `None` marks an argument whose type is only known at runtime.
+    pub fn check_arg_schemas(&self, name: &str, schemas: &[Option<SchemaNode>]) -> Result<(), String> {
+        for (i, schema) in schemas.iter().enumerate() {
+            if let Some(schema) = schema {
+                let expected = self.arg_type(i);
+                if !expected.accepts_schema(schema) {
+                    return Err(format!("Function {} argument {} expects {:?}, got {:?}", name, i + 1, expected, schema));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+static FUNCTIONS: phf::Map<&'static str, FunctionDef> = phf_map! {
+    "ABS" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(abs) },
+    "SIGN" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(sign) },
+    "ROUND" => FunctionDef { args: &[ArgType::Number, ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(round) },
+    "TRUNC" => FunctionDef { args: &[ArgType::Number, ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(trunc) },
+    "FLOOR" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(floor) },
+    "CEIL" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(ceil) },
+    "SQRT" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(sqrt) },
+    "POWER" => FunctionDef { args: &[ArgType::Number, ArgType::Number], min_args: 2, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(power) },
+    "EXP" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(exp) },
+    "LN" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(ln) },
+    "LOG10" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(log10) },
+    "SIN" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(sin) },
+    "COS" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(cos) },
+    "TAN" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(tan) },
+    "ASIN" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(asin) },
+    "ACOS" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(acos) },
+    "ATAN" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(atan) },
+    "ATAN2" => FunctionDef { args: &[ArgType::Number, ArgType::Number], min_args: 2, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(atan2) },
+    "DEGREES" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(degrees) },
+    "RADIANS" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(radians) },
+    "PI" => FunctionDef { args: &[], min_args: 0, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(pi) },
+    "GREATEST" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: true, strict: false, returns: ArgType::Number, func: FunctionImpl::Scalar(greatest) },
+    "LEAST" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: true, strict: false, returns: ArgType::Number, func: FunctionImpl::Scalar(least) },
+    "WIDTH_BUCKET" => FunctionDef { args: &[ArgType::Number, ArgType::Number, ArgType::Number, ArgType::Number], min_args: 4, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(width_bucket) },
+    "CLAMP" => FunctionDef { args: &[ArgType::Number, ArgType::Number, ArgType::Number], min_args: 3, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(clamp) },
+    "BIT_COUNT" => FunctionDef { args: &[ArgType::Number], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(bit_count) },
+    "GET_BIT" => FunctionDef { args: &[ArgType::Number, ArgType::Number], min_args: 2, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(get_bit) },
+    "LOWER" => FunctionDef { args: &[ArgType::String], min_args: 1, variadic: false, strict: true, returns: ArgType::String, func: FunctionImpl::Scalar(lower) },
+    "UPPER" => FunctionDef { args: &[ArgType::String], min_args: 1, variadic: false, strict: true, returns: ArgType::String, func: FunctionImpl::Scalar(upper) },
+    "LENGTH" => FunctionDef { args: &[ArgType::Any], min_args: 1, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(length) },
+    "CONCAT" => FunctionDef { args: &[ArgType::Any], min_args: 1, variadic: true, strict: false, returns: ArgType::String, func: FunctionImpl::Scalar(concat) },
+    "IS_NULL" => FunctionDef { args: &[ArgType::Any], min_args: 1, variadic: false, strict: false, returns: ArgType::Bool, func: FunctionImpl::Scalar(is_null) },
+    "IS_MISSING" => FunctionDef { args: &[ArgType::Any], min_args: 1, variadic: false, strict: false, returns: ArgType::Bool, func: FunctionImpl::Scalar(is_missing) },
+    "COALESCE" => FunctionDef { args: &[ArgType::Any], min_args: 1, variadic: true, strict: false, returns: ArgType::Any, func: FunctionImpl::Scalar(coalesce) },
+    "IFNULL" => FunctionDef { args: &[ArgType::Any, ArgType::Any], min_args: 2, variadic: false, strict: false, returns: ArgType::Any, func: FunctionImpl::Scalar(coalesce) },
+    "NULLIF" => FunctionDef { args: &[ArgType::Any, ArgType::Any], min_args: 2, variadic: false, strict: false, returns: ArgType::Any, func: FunctionImpl::Scalar(nullif) },
+    "ARRAY_CONSTRUCT" => FunctionDef { args: &[ArgType::Any], min_args: 0, variadic: true, strict: false, returns: ArgType::Array, func: FunctionImpl::Scalar(array_construct) },
+    "OBJECT_CONSTRUCT" => FunctionDef { args: &[ArgType::Any], min_args: 0, variadic: true, strict: false, returns: ArgType::Object, func: FunctionImpl::Scalar(object_construct) },
+    "NOW" => FunctionDef { args: &[], min_args: 0, variadic: false, strict: false, returns: ArgType::Timestamp, func: FunctionImpl::Scalar(now) },
+    "CURRENT_TIMESTAMP" => FunctionDef { args: &[], min_args: 0, variadic: false, strict: false, returns: ArgType::Timestamp, func: FunctionImpl::Scalar(now) },
+    "DATE_TRUNC" => FunctionDef { args: &[ArgType::String, ArgType::Timestamp], min_args: 2, variadic: false, strict: true, returns: ArgType::Timestamp, func: FunctionImpl::Scalar(date_trunc) },
+    "DATE_PART" => FunctionDef { args: &[ArgType::String, ArgType::Timestamp], min_args: 2, variadic: false, strict: true, returns: ArgType::Number, func: FunctionImpl::Scalar(date_part) },
+    "FORMAT_TIMESTAMP" => FunctionDef { args: &[ArgType::String, ArgType::Timestamp, ArgType::String], min_args: 2, variadic: false, strict: true, returns: ArgType::String, func: FunctionImpl::Scalar(format_timestamp) },
+    "CONVERT_TIMEZONE" => FunctionDef { args: &[ArgType::String, ArgType::Timestamp], min_args: 2, variadic: false, strict: true, returns: ArgType::Timestamp, func: FunctionImpl::Scalar(convert_timezone) },
+    "REGEXP_LIKE" => FunctionDef { args: &[ArgType::String, ArgType::String], min_args: 2, variadic: false, strict: true, returns: ArgType::Bool, func: FunctionImpl::Pattern(regexp_like) },
+    "REGEXP_EXTRACT" => FunctionDef { args: &[ArgType::String, ArgType::String, ArgType::Number], min_args: 2, variadic: false, strict: true, returns: ArgType::String, func: FunctionImpl::Pattern(regexp_extract) },
+    "REGEXP_REPLACE" => FunctionDef { args: &[ArgType::String, ArgType::String, ArgType::String], min_args: 3, variadic: false, strict: true, returns: ArgType::String, func: FunctionImpl::Pattern(regexp_replace) },
+};
+
+pub fn lookup(name: &str) -> Result<&'static FunctionDef, String> {
+    FUNCTIONS.get(name).ok_or(format!("Unknown function: {}", name))
+}
+
+/// Calls the function `def` of `name` with arguments already counted when the
+/// query was built, only their values are checked. `compiled` is the regex
+/// compiled when the query was built and pattern functions compile their
+/// argument when it is missing.
+pub fn call(def: &FunctionDef, name: &str, args: &[&SQLValue], compiled: Option<&Regex>) -> Result<SQLValue, String> {
+    if def.strict {
+        if let Some(unknown) = SQLValue::unknown_of(args) {
+            return Ok(unknown);
+        }
+    }
+
+    for (i, arg) in args.iter().enumerate() {
+        let expected = def.arg_type(i);
+        if !arg.is_unknown() && !expected.accepts(arg) {
+            return Err(format!("Function {} argument {} expects {:?}, got {}", name, i + 1, expected, (*arg).clone().into_json()));
+        }
+    }
+
+    match def.func {
+        FunctionImpl::Scalar(func) => func(args),
+        FunctionImpl::Pattern(func) => match compiled {
+            Some(regex) => func(args, regex),
+            None => func(args, &pattern::compile(&MatchKind::Regex, json_arg(args, 1).as_str().unwrap())?),
+        }
+    }
+}
+
+/// Argument already checked against a JSON `ArgType` by `call`
+fn json_arg<'a>(args: &[&'a SQLValue], position: usize) -> &'a serde_json::Value {
+    args[position].as_json().unwrap()
+}
+
+/// Argument already checked against `ArgType::Timestamp` by `call`
+fn timestamp_arg(args: &[&SQLValue], position: usize) -> Result<time::Timestamp, String> {
+    time::to_timestamp(args[position])
+}
+
+/// Argument already checked against `ArgType::Number` by `call`
+fn number_arg(args: &[&SQLValue], position: usize) -> JsonNumber {
+    JsonNumber::from(json_arg(args, position).as_number().unwrap())
+}
+
+fn integer_arg(name: &str, args: &[&SQLValue], position: usize) -> Result<i64, String> {
+    let arg = json_arg(args, position);
+    arg.as_i64().ok_or(format!("Function {} argument {} must be an integer, got {}", name, position + 1, arg))
+}
+
+fn number_result(n: JsonNumber) -> Result<SQLValue, String> {
+    Ok(SQLValue::from(serde_json::Value::Number(n.to_number()?)))
+}
+
+fn abs(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).abs())
+}
+
+fn sign(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).signum())
+}
+
+/// Rounds to a number of decimal places, 0 by default
+fn round(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    let digits = if args.len() > 1 { integer_arg("ROUND", args, 1)? } else { 0 };
+    number_result(number_arg(args, 0).round(digits))
+}
+
+fn trunc(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    let digits = if args.len() > 1 { integer_arg("TRUNC", args, 1)? } else { 0 };
+    number_result(number_arg(args, 0).trunc(digits))
+}
+
+fn floor(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).floor())
+}
+
+fn ceil(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).ceil())
+}
+
+fn sqrt(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_sqrt()?)
+}
+
+fn power(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_pow(number_arg(args, 1))?)
+}
+
+fn exp(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_apply("EXP", f64::exp)?)
+}
+
+fn ln(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_apply("LN", |x| if x > 0.0 { x.ln() } else { f64::NAN })?)
+}
+
+fn log10(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_apply("LOG10", |x| if x > 0.0 { x.log10() } else { f64::NAN })?)
+}
+
+fn sin(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_apply("SIN", f64::sin)?)
+}
+
+fn cos(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_apply("COS", f64::cos)?)
+}
+
+fn tan(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_apply("TAN", f64::tan)?)
+}
+
+fn asin(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_apply("ASIN", f64::asin)?)
+}
+
+fn acos(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_apply("ACOS", f64::acos)?)
+}
+
+fn atan(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_apply("ATAN", f64::atan)?)
+}
+
+/// Angle of the point (x, y), called as `ATAN2(y, x)`
+fn atan2(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(JsonNumber::from(number_arg(args, 0).as_f64().atan2(number_arg(args, 1).as_f64())))
+}
+
+fn degrees(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_apply("DEGREES", f64::to_degrees)?)
+}
+
+fn radians(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).checked_apply("RADIANS", f64::to_radians)?)
+}
+
+fn pi(_args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(JsonNumber::from(std::f64::consts::PI))
+}
+
+/// Extreme argument by `ordering`, NULL and MISSING arguments are skipped and the
+/// result is NULL when nothing is left
+fn extreme(args: &[&SQLValue], ordering: Ordering) -> Result<SQLValue, String> {
+    let mut res: Option<&SQLValue> = None;
+    for arg in args.iter().filter(|x| !x.is_unknown()) {
+        let replace = match res {
+            Some(current) => {
+                let n1 = JsonNumber::from(arg.as_json().unwrap().as_number().unwrap());
+                let n2 = JsonNumber::from(current.as_json().unwrap().as_number().unwrap());
+                n1.partial_cmp(&n2) == Some(ordering)
+            },
+            None => true,
+        };
+        if replace {
+            res = Some(arg);
+        }
+    }
+
+    Ok(res.cloned().unwrap_or(SQLValue::from(serde_json::Value::Null)))
+}
+
+fn greatest(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    extreme(args, Ordering::Greater)
+}
+
+fn least(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    extreme(args, Ordering::Less)
+}
+
+/// Bucket of `x` among `count` equal width buckets between `low` and `high`, 0 below
+/// the range and `count + 1` above it. `low` may be greater than `high` for
+/// descending buckets.
+fn width_bucket(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    let (x, low, high) = (number_arg(args, 0).as_f64(), number_arg(args, 1).as_f64(), number_arg(args, 2).as_f64());
+    let count = integer_arg("WIDTH_BUCKET", args, 3)?;
+    if count <= 0 {
+        return Err(format!("Function WIDTH_BUCKET bucket count must be positive, got {}", count));
+    }
+    if low == high {
+        return Err("Function WIDTH_BUCKET bounds must differ".to_string());
+    }
+
+    let below = if low < high { x < low } else { x > low };
+    let above = if low < high { x >= high } else { x <= high };
+    let bucket = if below {
+        0
+    } else if above {
+        count + 1
+    } else {
+        (((x - low) / (high - low) * count as f64).floor() as i64 + 1).min(count)
+    };
+
+    number_result(JsonNumber::from(bucket))
+}
+
+fn clamp(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    let (x, min, max) = (number_arg(args, 0), number_arg(args, 1), number_arg(args, 2));
+    if min > max {
+        return Err(format!("Function CLAMP lower bound {} is greater than upper bound {}", json_arg(args, 1), json_arg(args, 2)));
+    }
+
+    if x < min {
+        Ok(args[1].clone())
+    } else if x > max {
+        Ok(args[2].clone())
+    } else {
+        Ok(args[0].clone())
+    }
+}
+
+fn bit_count(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).bit_count()?)
+}
+
+fn get_bit(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    number_result(number_arg(args, 0).get_bit(number_arg(args, 1))?)
+}
+
+fn lower(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    Ok(SQLValue::from(serde_json::Value::String(json_arg(args, 0).as_str().unwrap().to_lowercase())))
+}
+
+fn upper(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    Ok(SQLValue::from(serde_json::Value::String(json_arg(args, 0).as_str().unwrap().to_uppercase())))
+}
+
+fn length(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    match args[0].as_json() {
+        Some(serde_json::Value::String(s)) => Ok(SQLValue::from(serde_json::json!(s.chars().count()))),
+        Some(serde_json::Value::Array(arr)) => Ok(SQLValue::from(serde_json::json!(arr.len()))),
+        Some(serde_json::Value::Object(map)) => Ok(SQLValue::from(serde_json::json!(map.len()))),
+        _ => Err(format!("Function LENGTH not implemented for {}", args[0].clone().into_json())),
+    }
+}
+
+fn concat(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    let concatenated = args.iter().map(|arg| match (*arg).clone().into_json() {
+        serde_json::Value::Null => "".to_string(),
+        serde_json::Value::String(s) => s,
+        other => other.to_string(),
+    }).collect::<String>();
+
+    Ok(SQLValue::from(serde_json::Value::String(concatenated)))
+}
+
+fn is_null(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    Ok(SQLValue::from(serde_json::Value::Bool(args[0].is_unknown())))
+}
+
+fn is_missing(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    Ok(SQLValue::from(serde_json::Value::Bool(args[0].is_missing())))
+}
+
+/// First argument that is neither NULL nor MISSING, MISSING only when every argument is
+fn coalesce(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    match args.iter().find(|x| !x.is_unknown()) {
+        Some(value) => Ok((*value).clone()),
+        None if args.iter().all(|x| x.is_missing()) => Ok(SQLValue::Missing),
+        None => Ok(SQLValue::from(serde_json::Value::Null)),
+    }
+}
+
+fn nullif(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    if args[0].is_unknown() || args[1].is_unknown() {
+        return Ok(args[0].clone());
+    }
+
+    let equal = execute::execute_binary_op((args[0], args[1]), &BinaryOperator::Eq)?;
+    if equal == SQLValue::from(serde_json::Value::Bool(true)) {
+        Ok(SQLValue::from(serde_json::Value::Null))
+    } else {
+        Ok(args[0].clone())
+    }
+}
+
+/// MISSING elements become NULL so that positions are kept
+fn array_construct(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    let elems = args.iter().map(|x| (*x).clone().into_json()).collect();
+    Ok(SQLValue::from(serde_json::Value::Array(elems)))
+}
+
+/// Alternating keys and values, members with a MISSING value are left out
+fn object_construct(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    if args.len() % 2 != 0 {
+        return Err(format!("Function OBJECT_CONSTRUCT expects key value pairs, got {} arguments", args.len()));
+    }
+
+    let mut members = serde_json::Map::new();
+    for pair in args.chunks(2) {
+        let key = match pair[0].as_json() {
+            Some(serde_json::Value::String(key)) => key,
+            _ => return Err(format!("Function OBJECT_CONSTRUCT keys must be strings, got {}", pair[0].clone().into_json())),
+        };
+        if !pair[1].is_missing() {
+            members.insert(key.clone(), pair[1].clone().into_json());
+        }
+    }
+
+    Ok(SQLValue::from(serde_json::Value::Object(members)))
+}
+
+fn now(_args: &[&SQLValue]) -> Result<SQLValue, String> {
+    Ok(SQLValue::Timestamp(chrono::Utc::now().fixed_offset()))
+}
+
+fn date_trunc(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    Ok(SQLValue::Timestamp(time::truncate(&timestamp_arg(args, 1)?, json_arg(args, 0).as_str().unwrap())?))
+}
+
+fn date_part(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    let part = time::date_part(&timestamp_arg(args, 1)?, json_arg(args, 0).as_str().unwrap())?;
+    Ok(SQLValue::from(serde_json::Value::Number(part.to_number()?)))
+}
+
+/// strftime style format, in the given time zone when there is one
+fn format_timestamp(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    let mut ts = timestamp_arg(args, 1)?;
+    if args.len() > 2 {
+        ts = time::convert_timezone(&ts, json_arg(args, 2).as_str().unwrap())?;
+    }
+    Ok(SQLValue::from(serde_json::Value::String(time::format_timestamp_with(&ts, json_arg(args, 0).as_str().unwrap())?)))
+}
+
+fn convert_timezone(args: &[&SQLValue]) -> Result<SQLValue, String> {
+    Ok(SQLValue::Timestamp(time::convert_timezone(&timestamp_arg(args, 1)?, json_arg(args, 0).as_str().unwrap())?))
+}
+
+fn regexp_like(args: &[&SQLValue], regex: &Regex) -> Result<SQLValue, String> {
+    Ok(SQLValue::from(serde_json::Value::Bool(regex.is_match(json_arg(args, 0).as_str().unwrap()))))
+}
+
+/// Group of the first match, the whole match by default, NULL without a match
+fn regexp_extract(args: &[&SQLValue], regex: &Regex) -> Result<SQLValue, String> {
+    let group = match args.get(2).map(|_| json_arg(args, 2)) {
+        Some(group) => group.as_u64().ok_or(format!("REGEXP_EXTRACT group must be a non-negative integer, got {}", group))? as usize,
+        None => 0,
+    };
+
+    let res = regex.captures(json_arg(args, 0).as_str().unwrap())
+        .and_then(|captures| captures.get(group))
+        .map_or(serde_json::Value::Null, |x| serde_json::Value::String(x.as_str().to_string()));
+
+    Ok(SQLValue::from(res))
+}
+
+/// Replaces every match, the replacement refers to groups as `$1` or `${name}`
+fn regexp_replace(args: &[&SQLValue], regex: &Regex) -> Result<SQLValue, String> {
+    let replaced = regex.replace_all(json_arg(args, 0).as_str().unwrap(), json_arg(args, 2).as_str().unwrap());
+    Ok(SQLValue::from(serde_json::Value::String(replaced.into_owned())))
+}
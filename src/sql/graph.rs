@@ -1,12 +1,18 @@
-use std::{collections::HashMap, io::Write};
+use std::{collections::{HashMap, HashSet}, io::Write};
 
 
 use base64::prelude::*;
 use graphviz_rust::dot_structures::{Attribute, GraphAttributes, Id, Stmt};
 use petgraph::{graph::NodeIndex, stable_graph::StableDiGraph, visit::{EdgeRef, IntoNodeReferences}};
-use sqlparser::ast::{Expr, SelectItem};
+use sqlparser::ast::{self as ast, BinaryOperator, CastFormat, DataType, DateTimeField, Expr, Function, FunctionArg, FunctionArgExpr, Ident, JsonOperator, SelectItem, UnaryOperator, Value};
 
-use super::types::{QueryTask, SQLLiteral, TaskAction, TaskContext};
+use super::aggregate;
+use super::cast;
+use super::dialect;
+use super::functions;
+use super::schema::SchemaNode;
+use super::time;
+use super::types::{AccessorSegment, CastType, MatchKind, QueryTask, SQLLiteral, TaskAction, TaskContext};
 
 
 pub fn print_graph(task_graph: &StableDiGraph<QueryTask, usize>) {
@@ -113,24 +119,9 @@ pub fn add_expr(
     alias: Option<String>,
 ) -> Result<NodeIndex, String> {
     match expr {
-        Expr::Identifier(id) => {
-            let node = task_graph.add_node(QueryTask {
-                alias: alias,
-                action: TaskAction::Accessor(Vec::from([id.to_string()])),
-                required: false,
-                context: None,
-            });
-            Ok(node)
-        }
-        Expr::CompoundIdentifier(ids) => {
-            let multi_accessor = ids.iter().map(|x| x.to_string()).collect::<Vec<String>>();
-            let node = task_graph.add_node(QueryTask {
-                alias: alias,
-                action: TaskAction::Accessor(multi_accessor),
-                required: false,
-                context: None,
-            });
-            Ok(node)
+        Expr::Identifier(_) | Expr::CompoundIdentifier(_)
+        | Expr::CompositeAccess { .. } | Expr::ArrayIndex { .. } | Expr::MapAccess { .. } => {
+            add_accessor(task_graph, expr, alias)
         }
         Expr::UnaryOp { op, expr } => {
             let parent_node = add_expr(task_graph, *expr, None)?;
@@ -158,70 +149,131 @@ pub fn add_expr(
 
             Ok(child_node)
         }
-        // Expr::Function(func) => {
-        //     let mut args = Vec::<NodeIndex>::new();
-        //     for arg in func.args {
-        //         match arg {
-        //             FunctionArg::Named { arg, name } => {
-        //                 return Err("Named arguments not supported".to_string());
-        //             },
-        //             FunctionArg::Unnamed(arg) => {
-        //                 args.push(graph_add_expr(task_graph, arg, None)?);
-        //             },
-        //             _ => {
-        //                 return Err("Not implemented".to_string());
-        //             }
-        //         }
-        //         args.push(graph_add_expr(task_graph, arg, None)?);
-        //     }
-
-        //     let node = task_graph.add_node(QueryTask {
-        //         alias: alias,
-        //         action: QueryAction::Function(func.name.to_string()),
-        //         required: false,
-        //     });
-
-        //     args.iter().for_each(|idx| {
-        //         task_graph.add_edge(*idx, node, 1);
-        //     });
-
-        //     Ok(node)
-        // }
-        Expr::Value(sqlparser::ast::Value::Number(str, _bool)) => {
-            let sql_literal = if str.contains(".") {
-                let number = str.parse::<f64>();
-                if number.is_err() {
-                    Err(number.unwrap_err().to_string())
-                } else {
-                    Ok(SQLLiteral::Float(number.unwrap()))
-                }
-            } else {
-                let number = str.parse::<i64>();
-                if number.is_err() {
-                    Err(number.unwrap_err().to_string())
-                } else {
-                    Ok(SQLLiteral::Integer(number.unwrap()))
+        Expr::Function(func) => {
+            if let Some(literal) = object_literal(&func) {
+                return add_literal(task_graph, literal, alias);
+            }
+
+            let name = func.name.to_string().to_uppercase();
+            let is_aggregate = aggregate::is_aggregate(&name);
+            if func.over.is_some() || func.filter.is_some() || (func.distinct && !is_aggregate) || !func.order_by.is_empty() {
+                return Err(format!("Unsupported function modifiers: {}", func));
+            }
+
+            let count_rows = name == "COUNT" && func.args.len() == 1;
+
+            let mut args = Vec::<Expr>::new();
+            for arg in func.args {
+                match arg {
+                    FunctionArg::Unnamed(FunctionArgExpr::Expr(arg)) => {
+                        args.push(arg);
+                    },
+                    // COUNT(*) counts rows, it is COUNT without arguments
+                    FunctionArg::Unnamed(FunctionArgExpr::Wildcard) if count_rows => {},
+                    FunctionArg::Named { .. } => {
+                        return Err("Named arguments not supported".to_string());
+                    },
+                    _ => {
+                        return Err(format!("Unsupported function argument: {}", arg));
+                    }
                 }
-            }?;
+            }
 
-            let node = task_graph.add_node(QueryTask {
-                alias: alias,
-                action: TaskAction::Literal(sql_literal),
-                required: false,
-                context: None,
-            });
-            Ok(node)
+            if is_aggregate {
+                return add_multi_parent(task_graph, TaskAction::Aggregate { name, distinct: func.distinct }, args, alias);
+            }
+
+            add_function(task_graph, name, args, alias)
         }
-        Expr::Value(sqlparser::ast::Value::SingleQuotedString(str)) => {
-            let node = task_graph.add_node(QueryTask {
-                alias: alias,
-                action: TaskAction::Literal(SQLLiteral::String(str)),
-                required: false,
-                context: None,
+        Expr::ArrayAgg(array_agg) => {
+            if array_agg.order_by.is_some() || array_agg.limit.is_some() || array_agg.within_group {
+                return Err(format!("Unsupported ARRAY_AGG modifiers: {}", array_agg));
+            }
+            let action = TaskAction::Aggregate { name: "ARRAY_AGG".to_string(), distinct: array_agg.distinct };
+            add_multi_parent(task_graph, action, vec![*array_agg.expr], alias)
+        }
+        Expr::Case { operand, conditions, results, else_result } => {
+            let has_operand = operand.is_some();
+            let has_else = else_result.is_some();
+
+            // Parents are ordered: [operand], (condition, result)*, [else]
+            let mut branches = Vec::<Expr>::new();
+            if let Some(operand) = operand {
+                branches.push(*operand);
+            }
+            conditions.into_iter().zip(results.into_iter()).for_each(|(cond, res)| {
+                branches.push(cond);
+                branches.push(res);
             });
+            if let Some(else_result) = else_result {
+                branches.push(*else_result);
+            }
 
-            Ok(node)
-        },
+            add_multi_parent(task_graph, TaskAction::Case { has_operand, has_else }, branches, alias)
+        }
+        Expr::Cast { expr, data_type, format } => {
+            add_cast(task_graph, *expr, data_type, format, false, alias)
+        }
+        Expr::TryCast { expr, data_type, format } | Expr::SafeCast { expr, data_type, format } => {
+            add_cast(task_graph, *expr, data_type, format, true, alias)
+        }
+        Expr::InList { expr, list, negated } => {
+            let mut args = vec![*expr];
+            args.extend(list);
+            add_multi_parent(task_graph, TaskAction::InList { negated }, args, alias)
+        }
+        Expr::Between { expr, negated, low, high } => {
+            add_multi_parent(task_graph, TaskAction::Between { negated }, vec![*expr, *low, *high], alias)
+        }
+        Expr::Like { negated, expr, pattern, escape_char } => {
+            add_match(task_graph, MatchKind::Like { escape: escape_char }, negated, *expr, *pattern, alias)
+        }
+        Expr::ILike { negated, expr, pattern, escape_char } => {
+            add_match(task_graph, MatchKind::ILike { escape: escape_char }, negated, *expr, *pattern, alias)
+        }
+        Expr::SimilarTo { negated, expr, pattern, escape_char } => {
+            add_match(task_graph, MatchKind::SimilarTo { escape: escape_char }, negated, *expr, *pattern, alias)
+        }
+        Expr::RLike { negated, expr, pattern, regexp: _ } => {
+            add_match(task_graph, MatchKind::Regex, negated, *expr, *pattern, alias)
+        }
+        Expr::IsNull(expr) => {
+            add_function(task_graph, "IS_NULL".to_string(), vec![*expr], alias)
+        }
+        Expr::IsNotNull(expr) => {
+            add_expr(task_graph, Expr::UnaryOp { op: UnaryOperator::Not, expr: Box::new(Expr::IsNull(expr)) }, alias)
+        }
+        Expr::Value(value) => {
+            add_literal(task_graph, value_literal(&value)?, alias)
+        }
+        Expr::TypedString { data_type, value } => {
+            match cast::cast_type(&data_type)? {
+                CastType::Timestamp => add_literal(task_graph, SQLLiteral::Timestamp(time::parse_timestamp(&value)?), alias),
+                CastType::Interval => add_literal(task_graph, SQLLiteral::Interval(time::parse_interval(&value)?), alias),
+                _ => add_cast(task_graph, Expr::Value(Value::SingleQuotedString(value)), data_type, None, false, alias),
+            }
+        }
+        Expr::Interval(interval) => {
+            add_literal(task_graph, SQLLiteral::Interval(interval_literal(&interval)?), alias)
+        }
+        Expr::Extract { field, expr } => {
+            add_function(task_graph, "DATE_PART".to_string(), vec![Expr::Value(Value::SingleQuotedString(field.to_string())), *expr], alias)
+        }
+        Expr::AtTimeZone { timestamp, time_zone } => {
+            add_function(task_graph, "CONVERT_TIMEZONE".to_string(), vec![Expr::Value(Value::SingleQuotedString(time_zone)), *timestamp], alias)
+        }
+        Expr::Ceil { expr, field: DateTimeField::NoDateTime } => {
+            add_function(task_graph, "CEIL".to_string(), vec![*expr], alias)
+        }
+        Expr::Floor { expr, field: DateTimeField::NoDateTime } => {
+            add_function(task_graph, "FLOOR".to_string(), vec![*expr], alias)
+        }
+        Expr::Array(array) => {
+            match array.elem.iter().map(constant_literal).collect::<Option<Vec<_>>>() {
+                Some(elems) => add_literal(task_graph, SQLLiteral::Array(elems), alias),
+                None => add_function(task_graph, "ARRAY_CONSTRUCT".to_string(), array.elem, alias),
+            }
+        }
         Expr::Nested(nested_expr) => {
             add_expr(task_graph, *nested_expr, alias)
         },
@@ -229,7 +281,254 @@ pub fn add_expr(
     }
 }
 
-pub fn dealias(task_graph: &mut StableDiGraph<QueryTask, usize>, root_alias: String) -> Result<(), String> {
+fn add_cast(
+    task_graph: &mut StableDiGraph<QueryTask, usize>,
+    expr: Expr,
+    data_type: DataType,
+    format: Option<CastFormat>,
+    try_cast: bool,
+    alias: Option<String>,
+) -> Result<NodeIndex, String> {
+    if format.is_some() {
+        return Err("CAST with FORMAT not supported".to_string());
+    }
+
+    let target = cast::cast_type(&data_type)?;
+    let parent_node = add_expr(task_graph, expr, None)?;
+    let child_node = task_graph.add_node(QueryTask {
+        alias: alias,
+        action: TaskAction::Cast { target, try_cast },
+        required: false,
+        context: None,
+    });
+    task_graph.add_edge(parent_node, child_node, 1);
+    Ok(child_node)
+}
+
+fn add_match(
+    task_graph: &mut StableDiGraph<QueryTask, usize>,
+    kind: MatchKind,
+    negated: bool,
+    expr: Expr,
+    pattern: Expr,
+    alias: Option<String>,
+) -> Result<NodeIndex, String> {
+    let expr_node = add_expr(task_graph, expr, None)?;
+    let pattern_node = add_expr(task_graph, pattern, None)?;
+    let child_node = task_graph.add_node(QueryTask {
+        alias: alias,
+        action: TaskAction::Match { kind, negated },
+        required: false,
+        context: None,
+    });
+
+    task_graph.add_edge(expr_node, child_node, 1);
+    task_graph.add_edge(pattern_node, child_node, 2);
+
+    Ok(child_node)
+}
+
+fn add_literal(
+    task_graph: &mut StableDiGraph<QueryTask, usize>,
+    literal: SQLLiteral,
+    alias: Option<String>,
+) -> Result<NodeIndex, String> {
+    Ok(task_graph.add_node(QueryTask {
+        alias: alias,
+        action: TaskAction::Literal(literal),
+        required: false,
+        context: None,
+    }))
+}
+
+fn value_literal(value: &Value) -> Result<SQLLiteral, String> {
+    match value {
+        Value::Number(str, _) if str.contains(['.', 'e', 'E']) => {
+            str.parse::<f64>().map(SQLLiteral::Float).map_err(|e| e.to_string())
+        }
+        Value::Number(str, _) => str.parse::<i64>().map(SQLLiteral::Integer).map_err(|e| e.to_string()),
+        Value::SingleQuotedString(str) | Value::DoubleQuotedString(str) => Ok(SQLLiteral::String(str.clone())),
+        Value::Boolean(b) => Ok(SQLLiteral::Bool(*b)),
+        Value::Null => Ok(SQLLiteral::Null),
+        _ => Err(format!("Unsupported literal: {}", value)),
+    }
+}
+
+/// `INTERVAL '5 minutes'`, or `INTERVAL '5' MINUTE` with the unit as a field
+pub fn interval_literal(interval: &ast::Interval) -> Result<time::Interval, String> {
+    if interval.last_field.is_some() {
+        return Err(format!("Unsupported interval: {}", interval));
+    }
+
+    let value = match interval.value.as_ref() {
+        Expr::Value(Value::SingleQuotedString(value)) | Expr::Value(Value::Number(value, _)) => value.clone(),
+        _ => return Err(format!("Interval must be a literal: {}", interval)),
+    };
+
+    match &interval.leading_field {
+        Some(field) => time::parse_interval(&format!("{} {}", value, field)),
+        None => time::parse_interval(&value),
+    }
+}
+
+/// Literal of an expression built only from constants, so that array and
+/// object constructors of constants are initialized once like other literals
+fn constant_literal(expr: &Expr) -> Option<SQLLiteral> {
+    match expr {
+        Expr::Value(value) => value_literal(value).ok(),
+        Expr::UnaryOp { op: UnaryOperator::Minus, expr } => match constant_literal(expr)? {
+            SQLLiteral::Integer(i) => i.checked_neg().map(SQLLiteral::Integer),
+            SQLLiteral::Float(f) => Some(SQLLiteral::Float(-f)),
+            _ => None,
+        },
+        Expr::Nested(expr) => constant_literal(expr),
+        Expr::Array(array) => array.elem.iter().map(constant_literal).collect::<Option<Vec<_>>>().map(SQLLiteral::Array),
+        Expr::Function(func) => object_literal(func),
+        _ => None,
+    }
+}
+
+/// `OBJECT_CONSTRUCT` of constant key value pairs
+fn object_literal(func: &Function) -> Option<SQLLiteral> {
+    if !func.name.to_string().eq_ignore_ascii_case("OBJECT_CONSTRUCT") || func.args.len() % 2 != 0 {
+        return None;
+    }
+
+    let args = func.args.iter().map(|arg| match arg {
+        FunctionArg::Unnamed(FunctionArgExpr::Expr(arg)) => constant_literal(arg),
+        _ => None,
+    }).collect::<Option<Vec<_>>>()?;
+
+    args.chunks(2).map(|pair| match &pair[0] {
+        SQLLiteral::String(key) => Some((key.clone(), pair[1].clone())),
+        _ => None,
+    }).collect::<Option<Vec<_>>>().map(SQLLiteral::Object)
+}
+
+/// Adds an accessor node for an access chain. Chains starting from an identifier
+/// are linked to their alias by `dealias`, any other base is linked here.
+fn add_accessor(
+    task_graph: &mut StableDiGraph<QueryTask, usize>,
+    expr: Expr,
+    alias: Option<String>,
+) -> Result<NodeIndex, String> {
+    let (base, path) = accessor_path(expr)?;
+    let base_node = match base {
+        Some(base) => Some(add_expr(task_graph, base, None)?),
+        None => None,
+    };
+
+    let child_node = task_graph.add_node(QueryTask {
+        alias: alias,
+        action: TaskAction::Accessor(path),
+        required: false,
+        context: None,
+    });
+
+    if let Some(base_node) = base_node {
+        task_graph.add_edge(base_node, child_node, 1);
+    }
+
+    Ok(child_node)
+}
+
+/// Splits an access chain into the expression it starts from and its path,
+/// the base is `None` when the chain starts from an identifier
+fn accessor_path(expr: Expr) -> Result<(Option<Expr>, Vec<AccessorSegment>), String> {
+    match expr {
+        Expr::Identifier(id) => Ok((None, vec![AccessorSegment::Key(id.value)])),
+        Expr::CompoundIdentifier(ids) => Ok((None, ids.into_iter().map(|x| AccessorSegment::Key(x.value)).collect())),
+        Expr::CompositeAccess { expr, key } => {
+            let (base, mut path) = accessor_path(*expr)?;
+            path.push(AccessorSegment::Key(key.value));
+            Ok((base, path))
+        }
+        Expr::ArrayIndex { obj: inner, indexes } | Expr::MapAccess { column: inner, keys: indexes } => {
+            let (base, mut path) = accessor_path(*inner)?;
+            for index in indexes {
+                path.push(subscript_segment(index)?);
+            }
+            Ok((base, path))
+        }
+        other => Ok((Some(other), vec![])),
+    }
+}
+
+/// Subscripts are literals, a string is a key, an integer an index and `start:end` a slice.
+/// Wildcard and recursive steps are unquoted identifiers, see `dialect::rewrite_path_steps`.
+fn subscript_segment(index: Expr) -> Result<AccessorSegment, String> {
+    match index {
+        Expr::Value(Value::SingleQuotedString(key)) => Ok(AccessorSegment::Key(key)),
+        Expr::Identifier(Ident { value, quote_style: None }) if value == dialect::WILDCARD_KEY => Ok(AccessorSegment::Wildcard),
+        Expr::Identifier(Ident { value, quote_style: None }) if value.starts_with(dialect::DESCENDANT_PREFIX) => {
+            Ok(AccessorSegment::Descendant(value[dialect::DESCENDANT_PREFIX.len()..].to_string()))
+        }
+        Expr::JsonAccess { left, operator: JsonOperator::Colon, right } => {
+            Ok(AccessorSegment::Slice(slice_bound(*left)?, slice_bound(*right)?))
+        }
+        other => Ok(AccessorSegment::Index(subscript_integer(&other)?)),
+    }
+}
+
+fn slice_bound(bound: Expr) -> Result<Option<i64>, String> {
+    match bound {
+        Expr::Value(Value::Null) => Ok(None),
+        other => Ok(Some(subscript_integer(&other)?)),
+    }
+}
+
+fn subscript_integer(index: &Expr) -> Result<i64, String> {
+    let parsed = match index {
+        Expr::Value(Value::Number(n, _)) => n.parse::<i64>().ok(),
+        Expr::UnaryOp { op: UnaryOperator::Minus, expr } => match expr.as_ref() {
+            Expr::Value(Value::Number(n, _)) => n.parse::<i64>().ok().map(|x| -x),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    parsed.ok_or(format!("Subscript must be an integer or string literal, got {}", index))
+}
+
+/// Adds a function node with its arguments linked in order
+pub fn add_function(
+    task_graph: &mut StableDiGraph<QueryTask, usize>,
+    name: String,
+    args: Vec<Expr>,
+    alias: Option<String>,
+) -> Result<NodeIndex, String> {
+    let def = functions::lookup(&name)?;
+    add_multi_parent(task_graph, TaskAction::Function { name, def }, args, alias)
+}
+
+/// Adds a node with any number of parents linked in order
+fn add_multi_parent(
+    task_graph: &mut StableDiGraph<QueryTask, usize>,
+    action: TaskAction,
+    args: Vec<Expr>,
+    alias: Option<String>,
+) -> Result<NodeIndex, String> {
+    let args = args.into_iter()
+        .map(|arg| add_expr(task_graph, arg, None))
+        .collect::<Result<Vec<NodeIndex>, String>>()?;
+
+    let node = task_graph.add_node(QueryTask {
+        alias: alias,
+        action: action,
+        required: false,
+        context: None,
+    });
+
+    args.iter().enumerate().for_each(|(i, idx)| {
+        task_graph.add_edge(*idx, node, i + 1);
+    });
+
+    Ok(node)
+}
+
+/// Links identifiers to the alias they name, the root of the query or a capture
+/// of its FROM topic. Captures keep their name as the key they read.
+pub fn dealias(task_graph: &mut StableDiGraph<QueryTask, usize>, root_alias: String, captures: &[&str]) -> Result<(), String> {
     let mut aliases = HashMap::<String, NodeIndex>::new();
 
     for (node_idx, task) in task_graph.node_references() {
@@ -254,16 +553,30 @@ pub fn dealias(task_graph: &mut StableDiGraph<QueryTask, usize>, root_alias: Str
         .unwrap()
         .clone();
 
+    if let Some(name) = captures.iter().find(|x| **x == "payload" || **x == root_alias || aliases.contains_key(**x)) {
+        return Err(format!("Topic capture \"{}\" is already an alias", name));
+    }
+
     aliases.insert(root_alias, root_node);
+    let topic_node = task_graph.node_indices().find(|idx| task_graph[*idx].action == TaskAction::Topic);
 
     for idx in task_graph.node_indices().collect::<Vec<NodeIndex>>() {
         match &task_graph[idx].action {
             TaskAction::Accessor(ids) => {
-                let id0 = ids.first();
-                if id0.is_none() {
+                let id0 = match ids.first() {
+                    Some(AccessorSegment::Key(id0)) => id0,
+                    _ => continue,
+                };
+
+                // Accessors on an expression are already linked to it
+                if task_graph.edges_directed(idx, petgraph::Direction::Incoming).next().is_some() {
+                    continue;
+                }
+
+                if let Some(topic_node) = topic_node.filter(|_| captures.contains(&id0.as_str())) {
+                    task_graph.add_edge(topic_node, idx, 1);
                     continue;
                 }
-                let id0 = id0.unwrap();
 
                 if aliases.contains_key(id0) {
                     let alias = aliases.get(id0).unwrap();
@@ -290,7 +603,7 @@ pub fn dealias(task_graph: &mut StableDiGraph<QueryTask, usize>, root_alias: Str
                             ids.iter()
                                 .skip(1)
                                 .map(|x| x.clone())
-                                .collect::<Vec<String>>(),
+                                .collect::<Vec<AccessorSegment>>(),
                         );
                         task_graph.add_edge(*alias, idx, 1);
                     }
@@ -317,6 +630,67 @@ pub fn dealias(task_graph: &mut StableDiGraph<QueryTask, usize>, root_alias: Str
 }
 
 
+/// Turns expressions of the select items and HAVING that are equal to a GROUP BY key
+/// into `ANY_VALUE` of that key, so they read the key of their group. Expressions
+/// feeding a key or an aggregate stay per row, as do constants.
+pub fn link_group_keys(task_graph: &mut StableDiGraph<QueryTask, usize>, group_by: &[NodeIndex]) -> Result<(), String> {
+    if group_by.is_empty() {
+        return Ok(());
+    }
+
+    let aggregates = task_graph.node_indices().filter(|x| matches!(task_graph[*x].action, TaskAction::Aggregate { .. }));
+    let mut per_row_inputs = HashSet::<NodeIndex>::new();
+    let mut stack = group_by.iter().copied()
+        .chain(aggregates.flat_map(|x| task_graph.neighbors_directed(x, petgraph::Direction::Incoming)))
+        .collect::<Vec<_>>();
+    while let Some(idx) = stack.pop() {
+        if per_row_inputs.insert(idx) {
+            stack.extend(task_graph.neighbors_directed(idx, petgraph::Direction::Incoming));
+        }
+    }
+
+    let mut depends_on_row = HashSet::<NodeIndex>::new();
+    for idx in toposort(task_graph)? {
+        if matches!(task_graph[idx].action, TaskAction::Root | TaskAction::Topic)
+            || task_graph.neighbors_directed(idx, petgraph::Direction::Incoming).any(|x| depends_on_row.contains(&x)) {
+            depends_on_row.insert(idx);
+        }
+        if per_row_inputs.contains(&idx) || !depends_on_row.contains(&idx) {
+            continue;
+        }
+
+        if let Some(key) = group_by.iter().find(|key| same_expr(task_graph, **key, idx)) {
+            let edges = task_graph.edges_directed(idx, petgraph::Direction::Incoming).map(|x| x.id()).collect::<Vec<_>>();
+            edges.into_iter().for_each(|edge| { task_graph.remove_edge(edge); });
+            task_graph.add_edge(*key, idx, 1);
+            task_graph[idx].action = TaskAction::Aggregate { name: "ANY_VALUE".to_string(), distinct: false };
+        }
+    }
+
+    Ok(())
+}
+
+/// Nodes compute the same expression when they apply the same action to the same inputs in order
+fn same_expr(task_graph: &StableDiGraph<QueryTask, usize>, a: NodeIndex, b: NodeIndex) -> bool {
+    if a == b {
+        return true;
+    }
+    if task_graph[a].action != task_graph[b].action {
+        return false;
+    }
+
+    let parents = |idx: NodeIndex| {
+        let mut edges = task_graph.edges_directed(idx, petgraph::Direction::Incoming)
+            .map(|x| (*x.weight(), x.source()))
+            .collect::<Vec<_>>();
+        edges.sort();
+        edges.into_iter().map(|x| x.1).collect::<Vec<_>>()
+    };
+
+    let (parents_a, parents_b) = (parents(a), parents(b));
+    parents_a.len() == parents_b.len() && parents_a.iter().zip(parents_b.iter()).all(|(x, y)| same_expr(task_graph, *x, *y))
+}
+
 /// Traverses the given directed graph upward from the specified node index (`idx`),
 /// searching for the nearest ancestor with a non-empty alias or a non-Link action.
 /// Returns the index of the found ancestor, or `None` if no such ancestor is found.
@@ -339,8 +713,7 @@ pub fn find_parent_with_alias(
 }
 
 pub fn populate_context(task_graph: &mut StableDiGraph<QueryTask, usize>) -> Result<(), String> {
-    // TODO add function support
-        for idx in task_graph.node_indices().collect::<Vec<NodeIndex>>() {
+    for idx in task_graph.node_indices().collect::<Vec<NodeIndex>>() {
         if task_graph[idx].action == TaskAction::Finalize {
             continue;
         }
@@ -349,8 +722,14 @@ pub fn populate_context(task_graph: &mut StableDiGraph<QueryTask, usize>) -> Res
             TaskAction::Accessor(_) => Some(1),
             TaskAction::Link => Some(1),
             TaskAction::UnaryOp(_) => Some(1),
+            TaskAction::Cast { .. } => Some(1),
             TaskAction::BinaryOp(_) => Some(2),
-            TaskAction::_Function(_) => None,
+            TaskAction::Match { .. } => Some(2),
+            TaskAction::Function { .. } => None,
+            TaskAction::Aggregate { .. } => None,
+            TaskAction::Case { .. } => None,
+            TaskAction::InList { .. } => None,
+            TaskAction::Between { .. } => None,
             _ => Some(0),
         };
 
@@ -370,7 +749,7 @@ pub fn populate_context(task_graph: &mut StableDiGraph<QueryTask, usize>) -> Res
 
         let source_indexes = edges.iter().map(|x| x.source()).collect::<Vec<NodeIndex>>();
 
-        match task_graph[idx].action {
+        match task_graph[idx].action.clone() {
             TaskAction::Accessor(_) => {
                 task_graph[idx].context = Some(TaskContext::SingleParent(source_indexes[0]));
             },
@@ -380,10 +759,50 @@ pub fn populate_context(task_graph: &mut StableDiGraph<QueryTask, usize>) -> Res
             TaskAction::UnaryOp(_) => {
                 task_graph[idx].context = Some(TaskContext::SingleParent(source_indexes[0]));
             },
+            TaskAction::Cast { .. } => {
+                task_graph[idx].context = Some(TaskContext::SingleParent(source_indexes[0]));
+            },
             TaskAction::BinaryOp(_) => {
                 task_graph[idx].context = Some(TaskContext::DualParent(source_indexes[0], source_indexes[1]));
             },
-            TaskAction::_Function(_) => {
+            TaskAction::Match { .. } => {
+                task_graph[idx].context = Some(TaskContext::DualParent(source_indexes[0], source_indexes[1]));
+            },
+            TaskAction::Function { name, def } => {
+                def.check_arg_count(&name, source_indexes.len())?;
+
+                let arg_schemas = source_indexes.iter().map(|x| infer_type(task_graph, *x)).collect::<Vec<_>>();
+                def.check_arg_schemas(&name, &arg_schemas)?;
+
+                task_graph[idx].context = Some(TaskContext::MultiParent(source_indexes));
+            }
+            TaskAction::Aggregate { name, distinct } => {
+                aggregate::check_arg_count(&name, source_indexes.len())?;
+                if distinct && source_indexes.is_empty() {
+                    return Err(format!("Aggregate {} can't be DISTINCT without arguments", name));
+                }
+                task_graph[idx].context = Some(TaskContext::MultiParent(source_indexes));
+            }
+            TaskAction::Case { has_operand, has_else } => {
+                let branches = source_indexes.len().saturating_sub(has_operand as usize + has_else as usize);
+                if branches == 0 || branches % 2 != 0 {
+                    return Err(format!("Error CASE requires WHEN and THEN pairs: ({:?})", task_graph[idx]));
+                }
+
+                task_graph[idx].context = Some(TaskContext::MultiParent(source_indexes));
+            }
+            TaskAction::InList { .. } => {
+                if source_indexes.len() < 2 {
+                    return Err(format!("Error IN requires a list of values: ({:?})", task_graph[idx]));
+                }
+
+                task_graph[idx].context = Some(TaskContext::MultiParent(source_indexes));
+            }
+            TaskAction::Between { .. } => {
+                if let Some(part) = ["value", "lower bound", "upper bound"].get(source_indexes.len()) {
+                    return Err(format!("Error BETWEEN is missing its {}: ({:?})", part, task_graph[idx]));
+                }
+
                 task_graph[idx].context = Some(TaskContext::MultiParent(source_indexes));
             }
             _ => {}
@@ -394,7 +813,7 @@ pub fn populate_context(task_graph: &mut StableDiGraph<QueryTask, usize>) -> Res
             match &task.action {
                 TaskAction::Accessor(ids) => {
                     if task.context.is_none() {
-                        Err(ids.join("."))
+                        Err(ids.iter().map(|x| x.to_string()).collect::<String>().trim_start_matches('.').to_string())
                     } else {
                         Ok(())
                     }
@@ -412,6 +831,38 @@ pub fn populate_context(task_graph: &mut StableDiGraph<QueryTask, usize>) -> Res
     Ok(())
 }
 
+/// Returns the type a node is known to produce before any data is seen,
+/// or `None` when it depends on the input data.
+pub fn infer_type(task_graph: &StableDiGraph<QueryTask, usize>, idx: NodeIndex) -> Option<SchemaNode> {
+    match &task_graph[idx].action {
+        TaskAction::Literal(SQLLiteral::Integer(_)) => Some(SchemaNode::Number),
+        TaskAction::Literal(SQLLiteral::Float(_)) => Some(SchemaNode::Number),
+        TaskAction::Literal(SQLLiteral::String(_)) => Some(SchemaNode::String),
+        TaskAction::Literal(SQLLiteral::Bool(_)) => Some(SchemaNode::Bool),
+        TaskAction::Literal(SQLLiteral::Null) => Some(SchemaNode::Null),
+        TaskAction::Literal(SQLLiteral::Array(_)) => Some(SchemaNode::Array(None)),
+        TaskAction::Literal(SQLLiteral::Object(_)) => Some(SchemaNode::Object(None)),
+        TaskAction::Function { def, .. } => def.returns.to_schema(),
+        TaskAction::Aggregate { name, .. } if name == "COUNT" => Some(SchemaNode::Number),
+        TaskAction::Cast { target, try_cast: false } => target.to_schema(),
+        TaskAction::Cast { target, try_cast: true } => target.to_schema().map(|x| SchemaNode::Nullable(Box::new(x))),
+        TaskAction::BinaryOp(op) => match op {
+            BinaryOperator::Eq | BinaryOperator::NotEq
+            | BinaryOperator::Lt | BinaryOperator::LtEq
+            | BinaryOperator::Gt | BinaryOperator::GtEq => Some(SchemaNode::Nullable(Box::new(SchemaNode::Bool))),
+            BinaryOperator::StringConcat => Some(SchemaNode::Nullable(Box::new(SchemaNode::String))),
+            _ => None,
+        },
+        TaskAction::InList { .. } | TaskAction::Between { .. } | TaskAction::Match { .. } => Some(SchemaNode::Nullable(Box::new(SchemaNode::Bool))),
+        TaskAction::Accessor(ids) if ids.iter().any(|x| x.is_multi()) => Some(SchemaNode::Nullable(Box::new(SchemaNode::Array(None)))),
+        TaskAction::Link => {
+            let parent = task_graph.neighbors_directed(idx, petgraph::Direction::Incoming).next()?;
+            infer_type(task_graph, parent)
+        },
+        _ => None,
+    }
+}
+
 pub fn toposort(task_graph: &StableDiGraph<QueryTask, usize>) -> Result<Vec<NodeIndex>, String> {
     let sorted = petgraph::algo::toposort(task_graph, None);
     if sorted.is_err() {
@@ -419,4 +870,23 @@ pub fn toposort(task_graph: &StableDiGraph<QueryTask, usize>) -> Result<Vec<Node
     }
 
     Ok(sorted.unwrap())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn between_names_its_missing_part() {
+        let task = |action| QueryTask { alias: None, action, required: false, context: None };
+        let mut task_graph = StableDiGraph::new();
+        let between = task_graph.add_node(task(TaskAction::Between { negated: false }));
+        for weight in 1..=2 {
+            let value = task_graph.add_node(task(TaskAction::Literal(SQLLiteral::Integer(1))));
+            task_graph.add_edge(value, between, weight);
+        }
+
+        let err = populate_context(&mut task_graph).unwrap_err();
+        assert!(err.starts_with("Error BETWEEN is missing its upper bound"), "{}", err);
+    }
+}
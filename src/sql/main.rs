@@ -1,23 +1,18 @@
-use crate::sql::{execute, schema, parsing::parse_statement};
-
-use super::{parsing::parse, types::QueryResult};
+use super::{compiled::compile, types::{QueryResult, SQLValue}};
 
+/// Parses the statements and runs each once on `input_data`. Queries run on many
+/// messages should be compiled once with `compile` instead.
 pub fn parse_and_execute(sql_statement: String, input_data: &serde_json::Value) -> Result<Vec<Result<QueryResult, String>>, String> {
-    let ast = parse(sql_statement)?;
-    let parsed = ast.iter().map(|x| parse_statement((*x).clone())).collect::<Vec<_>>();
+    let queries = compile(sql_statement)?;
+    let message = SQLValue::from(input_data.clone());
 
     let mut results : Vec<Result<QueryResult, String>> = Vec::new();
 
-    for query in parsed {
-
-        if query.is_err() {
-            return Err(query.unwrap_err());
-        }
-
-        let mut query = query.unwrap();
-        let res = execute::execute_query(&mut query, input_data);
+    for query in queries {
+        let mut context = query.new_context();
+        let res = query.execute(&mut context, &message);
         results.push(res);
     }
 
     Ok(results)
-}
\ No newline at end of file
+}
@@ -1,14 +1,27 @@
 pub mod main;
 pub mod graph;
 pub mod parsing;
+pub mod dialect;
 pub mod debug;
 pub mod types;
 pub mod builder;
 pub mod sqlparser_helper;
 pub mod execute;
+pub mod cast;
+pub mod pattern;
+pub mod time;
+pub mod functions;
+pub mod aggregate;
+pub mod window;
+pub mod stream;
+pub mod compiled;
+pub mod topic;
+pub mod router;
 pub mod schema;
 
 #[cfg(test)]
 mod tests;
 
-pub use main::parse_and_execute;
\ No newline at end of file
+pub use main::parse_and_execute;
+pub use compiled::compile;
+pub use stream::parse_stream;
\ No newline at end of file
@@ -1,12 +1,19 @@
-use sqlparser::{ast::{SetExpr, Statement}, dialect::CustomDialect, parser::{Parser, ParserError}};
+use sqlparser::{ast::{Distinct, SetExpr, Statement}, parser::{Parser, ParserError}, tokenizer::{Location, Token, Tokenizer}};
 
-use super::{builder, types::{BuiltQuery}};
+use super::{builder, dialect::{self, DistilDialect, ForeachClauses}, types::{BuiltQuery}};
 
 // Parsing function uses custom dialect and returns parsed ast from sqlparser
-pub fn parse(query: String) -> Result<Vec<Statement>, String> {
-    let dialect = CustomDialect {};
+pub fn parse(query: String) -> Result<Vec<(Statement, ForeachClauses)>, String> {
+    let dialect = DistilDialect::new();
 
-    let ast = Parser::parse_sql(&dialect, query.as_str());
+    let ast = Tokenizer::new(&dialect, query.as_str())
+        .tokenize_with_location()
+        .map_err(ParserError::from)
+        .and_then(|tokens| {
+            let (tokens, distinct) = dialect::rewrite_return_distinct(dialect::rewrite_path_steps(tokens));
+            let mut parser = Parser::new(&dialect).with_tokens_with_locations(dialect::rewrite_exponents(tokens));
+            parse_statements(&mut parser, &distinct)
+        });
 
     ast.map_err(|x| match x {
         ParserError::TokenizerError(str) => format!("TokenizerError: {}", str),
@@ -15,18 +22,53 @@ pub fn parse(query: String) -> Result<Vec<Statement>, String> {
     })
 }
 
-pub fn parse_statement(stmt: Statement) -> Result<BuiltQuery, String> {
+/// `Parser::parse_statements`, also reading the clauses that follow a FOREACH.
+/// `distinct` has the locations of the FOREACH statements returning DISTINCT rows.
+fn parse_statements(parser: &mut Parser, distinct: &[Location]) -> Result<Vec<(Statement, ForeachClauses)>, ParserError> {
+    let mut statements = Vec::new();
+    let mut expecting_statement_delimiter = false;
+    loop {
+        while parser.consume_token(&Token::SemiColon) {
+            expecting_statement_delimiter = false;
+        }
+
+        if parser.peek_token().token == Token::EOF {
+            break;
+        }
+        if expecting_statement_delimiter {
+            return parser.expected("end of statement", parser.peek_token());
+        }
+
+        let location = parser.peek_token().location;
+        let statement = parser.parse_statement()?;
+        let clauses = match statement {
+            Statement::Foreach(_) => {
+                let distinct = distinct.contains(&location).then_some(Distinct::Distinct);
+                dialect::parse_foreach_clauses(parser, distinct)?
+            },
+            _ => ForeachClauses::default(),
+        };
+        statements.push((statement, clauses));
+        expecting_statement_delimiter = true;
+    }
+    Ok(statements)
+}
+
+pub fn parse_statement(stmt: Statement, clauses: ForeachClauses) -> Result<BuiltQuery, String> {
     let err_msg = format!("Error Query Type Unimplemented: {stmt:?}");
     match stmt.clone() {
         Statement::Foreach(ref foreach) => {
-            let mut foreach = builder::build_foreach_query(foreach)?;
+            let mut foreach = builder::build_foreach_query(foreach, clauses)?;
             foreach.sql_stmt = Some(stmt);
             Ok(BuiltQuery::FOREACH(foreach))
         }
         Statement::Query(query) => {
             match query.body.as_ref() {
                 SetExpr::Select(select_query) => {
-                    let mut select = builder::build_select_query(select_query.clone(), "payload".to_string())?;
+                    let mut select = builder::build_select_query(select_query.clone(), "payload".to_string(), &[])?;
+                    if select.is_aggregate() && !select.is_windowed() {
+                        return Err("Aggregate functions and GROUP BY are only supported in FOREACH RETURN and windowed SELECT".to_string());
+                    }
                     select.sql_stmt = Some(stmt);
                     Ok(BuiltQuery::SELECT(select))
                 },
@@ -36,4 +78,3 @@ pub fn parse_statement(stmt: Statement) -> Result<BuiltQuery, String> {
         _ => Err(err_msg)
     }
 }
-
@@ -0,0 +1,53 @@
+use regex::Regex;
+
+use super::types::MatchKind;
+
+/// Translates a SQL pattern to an anchored regex. LIKE only knows `%` and `_`,
+/// SIMILAR TO also keeps the regex operators `| * + ? {} () []`. The escape
+/// character makes the next character literal, backslash when none is given.
+fn translate_sql_pattern(pattern: &str, escape: Option<char>, similar: bool) -> Result<String, String> {
+    let escape = escape.unwrap_or('\\');
+    let mut regex = String::from("^(?s:");
+    let mut chars = pattern.chars();
+    let mut in_class = false;
+
+    while let Some(c) = chars.next() {
+        if c == escape {
+            match chars.next() {
+                Some(escaped) => regex.push_str(&regex::escape(&escaped.to_string())),
+                None => return Err(format!("Pattern {:?} ends with escape character", pattern)),
+            }
+            continue;
+        }
+
+        match c {
+            // Bracket expressions keep ranges and negation as they are
+            ']' if in_class => { in_class = false; regex.push(c) },
+            '-' | '^' if in_class => regex.push(c),
+            '[' if similar => { in_class = true; regex.push(c) },
+            _ if in_class => regex.push_str(&regex::escape(&c.to_string())),
+            '%' => regex.push_str(".*"),
+            '_' => regex.push('.'),
+            '|' | '*' | '+' | '?' | '{' | '}' | '(' | ')' if similar => regex.push(c),
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    if in_class {
+        return Err(format!("Pattern {:?} has an unterminated bracket expression", pattern));
+    }
+
+    regex.push_str(")$");
+    Ok(regex)
+}
+
+pub fn compile(kind: &MatchKind, pattern: &str) -> Result<Regex, String> {
+    let regex = match kind {
+        MatchKind::Like { escape } => translate_sql_pattern(pattern, *escape, false)?,
+        MatchKind::ILike { escape } => format!("(?i){}", translate_sql_pattern(pattern, *escape, false)?),
+        MatchKind::SimilarTo { escape } => translate_sql_pattern(pattern, *escape, true)?,
+        MatchKind::Regex => pattern.to_string(),
+    };
+
+    Regex::new(&regex).map_err(|e| format!("Invalid pattern {:?}: {}", pattern, e))
+}
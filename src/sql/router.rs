@@ -0,0 +1,48 @@
+use super::stream::StreamQuery;
+use super::topic::{topic_levels, TopicTree};
+use super::types::{SQLValue, StreamOutput};
+
+/// Outputs of the queries a message ran, by query index
+pub type RoutedOutputs = Vec<(usize, Result<Vec<StreamOutput>, String>)>;
+
+/// Stream queries by the topics they read from. A message on a topic only runs
+/// the queries whose FROM matches it, each keeping its own windows.
+#[derive(Debug, Default)]
+pub struct TopicRouter {
+    queries: Vec<StreamQuery>,
+    routes: TopicTree<usize>,
+}
+
+impl TopicRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a query, giving the index its outputs are returned with
+    pub fn add(&mut self, query: StreamQuery) -> Result<usize, String> {
+        let filter = query.topic_filter().ok_or("Query has no FROM topic".to_string())?.clone();
+        self.routes.insert(&filter, self.queries.len());
+        self.queries.push(query);
+        Ok(self.queries.len() - 1)
+    }
+
+    /// Runs the queries matching `topic` on the message, in the order they were
+    /// added, with what each gives for it
+    pub fn route(&mut self, topic: &str, payload: &SQLValue) -> Result<RoutedOutputs, String> {
+        let mut matched = self.routes.matches(&topic_levels(topic)?).into_iter().copied().collect::<Vec<_>>();
+        matched.sort_unstable();
+
+        Ok(matched.into_iter().map(|x| (x, self.queries[x].push_on(topic, payload))).collect())
+    }
+
+    /// Windows and sessions held by all the queries
+    #[cfg(test)]
+    pub fn window_count(&self) -> usize {
+        self.queries.iter().map(|x| x.window_count()).sum()
+    }
+
+    /// Ends the stream of every query, with the windows each closes
+    pub fn finish(&mut self) -> RoutedOutputs {
+        self.queries.iter_mut().map(|x| x.finish()).enumerate().collect()
+    }
+}
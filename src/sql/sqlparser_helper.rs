@@ -13,7 +13,7 @@ pub fn get_table_name(table: TableWithJoins) -> Result<String, String> {
             with_hints: _,
             version: _,
             partitions: _,
-        } => Ok(name.to_string()),
+        } => Ok(name.0.iter().map(|x| x.value.as_str()).collect::<Vec<_>>().join(".")),
         _ => Err("Unsupported table".to_string()),
     }
 }
\ No newline at end of file
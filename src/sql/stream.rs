@@ -0,0 +1,80 @@
+use super::compiled::{compile, CompiledQuery};
+use super::execute;
+use super::topic::TopicFilter;
+use super::types::{BuiltQuery, ExecutionContext, SQLValue, StreamOutput};
+use super::window::{LatePolicy, Lateness, WindowState};
+
+/// A query run over a stream of messages. Windowed queries keep their open
+/// windows between messages and give a result for each window as it closes,
+/// other queries give the result of every message on its own. Windows follow the
+/// event time of their messages, `Lateness` sets how out of order they may come.
+#[derive(Debug)]
+pub struct StreamQuery {
+    query: CompiledQuery,
+    context: ExecutionContext,
+    windows: WindowState,
+}
+
+impl StreamQuery {
+    pub fn new(query: CompiledQuery) -> Self {
+        StreamQuery {
+            context: query.new_context(),
+            query,
+            windows: WindowState::new(Lateness::default()),
+        }
+    }
+
+    pub fn with_lateness(mut self, lateness: Lateness) -> Result<Self, String> {
+        if lateness.out_of_orderness < 0 || lateness.allowed_lateness < 0 {
+            return Err(format!("Lateness can't be negative: {:?}", lateness));
+        }
+        if lateness.allowed_lateness > 0 && lateness.policy != LatePolicy::Correct {
+            return Err(format!("Allowed lateness needs the Correct late policy: {:?}", lateness));
+        }
+        self.windows.lateness = lateness;
+        Ok(self)
+    }
+
+    /// Runs the query on the next message of the stream, topic captures are MISSING
+    pub fn push(&mut self, data: &SQLValue) -> Result<Vec<StreamOutput>, String> {
+        self.push_with(SQLValue::Missing, data)
+    }
+
+    /// Runs the query on the next message of the stream, published on `topic`
+    pub fn push_on(&mut self, topic: &str, data: &SQLValue) -> Result<Vec<StreamOutput>, String> {
+        let captures = self.query.captures(topic)?;
+        self.push_with(captures, data)
+    }
+
+    fn push_with(&mut self, captures: SQLValue, data: &SQLValue) -> Result<Vec<StreamOutput>, String> {
+        self.context.set_topic(captures);
+        match self.query.query() {
+            BuiltQuery::SELECT(select) if select.is_windowed() => execute::execute_query_window(select, &mut self.context.main, &mut self.windows, data),
+            query => Ok(vec![StreamOutput::Result(execute::execute_query(query, &mut self.context, data)?)]),
+        }
+    }
+
+    /// Topics the query reads from
+    pub fn topic_filter(&self) -> Option<&TopicFilter> {
+        self.query.query().from()
+    }
+
+    /// Windows and sessions held for messages still to come
+    #[cfg(test)]
+    pub fn window_count(&self) -> usize {
+        self.windows.window_count()
+    }
+
+    /// Ends the stream, closing the windows that are still open
+    pub fn finish(&mut self) -> Result<Vec<StreamOutput>, String> {
+        match self.query.query() {
+            BuiltQuery::SELECT(select) if select.is_windowed() => execute::close_query_windows(select, &mut self.context.main, &mut self.windows),
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Parses every statement into a query ready to run over a stream
+pub fn parse_stream(sql_statement: String) -> Result<Vec<StreamQuery>, String> {
+    Ok(compile(sql_statement)?.into_iter().map(StreamQuery::new).collect())
+}
@@ -0,0 +1,696 @@
+use serde_json::json;
+
+use super::{compile, parse_and_execute, parse_stream};
+use super::router::TopicRouter;
+use super::types::{QueryResult, SimpleQueryResult, StreamOutput};
+use super::window::{LatePolicy, Lateness};
+
+fn select(sql: &str, data: &serde_json::Value) -> Result<SimpleQueryResult, String> {
+    let mut results = parse_and_execute(sql.to_string(), data)?;
+    match results.remove(0)? {
+        QueryResult::Simple(simple) => Ok(simple),
+        _ => Err("Expected simple result".to_string()),
+    }
+}
+
+fn values(sql: &str, data: &serde_json::Value) -> Vec<serde_json::Value> {
+    select(sql, data).unwrap().result.into_iter().map(|x| x.1).collect()
+}
+
+/// Rows of a FOREACH query that passed its WHEN condition
+fn foreach(sql: &str, data: &serde_json::Value) -> Vec<Vec<serde_json::Value>> {
+    let mut results = parse_and_execute(sql.to_string(), data).unwrap();
+    match results.remove(0).unwrap() {
+        QueryResult::Nested(nested) => nested.result.into_iter().map(|x| match x.unwrap() {
+            QueryResult::Simple(simple) => simple,
+            _ => panic!("Expected simple result"),
+        }).filter(|x| x.passed()).map(|x| x.result.into_iter().map(|x| x.1).collect()).collect(),
+        _ => panic!("Expected nested result"),
+    }
+}
+
+/// Rows passing their condition of each result a stream query gives for each
+/// message, the results given when the stream ends come last
+fn stream(sql: &str, messages: &[serde_json::Value]) -> Vec<Vec<Vec<Vec<serde_json::Value>>>> {
+    let mut query = parse_stream(sql.to_string()).unwrap().remove(0);
+    let mut results = messages.iter().map(|x| query.push(&x.clone().into()).unwrap()).collect::<Vec<_>>();
+    results.push(query.finish().unwrap());
+    results.into_iter().map(|x| x.into_iter().map(|x| match x {
+        StreamOutput::Result(result) => rows(result),
+        other => panic!("Expected result, got {:?}", other),
+    }).collect()).collect()
+}
+
+fn rows(result: QueryResult) -> Vec<Vec<serde_json::Value>> {
+    let simple = match result {
+        QueryResult::Simple(simple) => vec![simple],
+        QueryResult::Nested(nested) => nested.result.into_iter().map(|x| match x.unwrap() {
+            QueryResult::Simple(simple) => simple,
+            _ => panic!("Expected simple result"),
+        }).collect(),
+    };
+    simple.into_iter().filter(|x| x.passed()).map(|x| x.result.into_iter().map(|x| x.1).collect()).collect()
+}
+
+#[test]
+fn scalar_functions() {
+    let data = json!({"name": "Sensor", "value": -5, "tags": ["a", "b"]});
+    let res = values("SELECT ABS(payload.value), lower(payload.name), UPPER(payload.name), LENGTH(payload.tags) FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(5), json!("sensor"), json!("SENSOR"), json!(2)]);
+}
+
+#[test]
+fn scalar_function_checks() {
+    let data = json!({"name": "Sensor"});
+    assert!(select("SELECT ABS() FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT ABS('abc') FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT NOPE(1) FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT ABS(payload.name) FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn string_operators() {
+    let data = json!({"status": "ok", "id": "42", "level": "warn"});
+    let res = select("SELECT payload.status || '-' || payload.id AS s FROM \"/topic\" WHERE payload.status = 'ok'", &data).unwrap();
+    assert_eq!(res.result[0].1, json!("ok-42"));
+    assert_eq!(res.cond, Some(json!(true)));
+
+    let res = values("SELECT payload.level < 'zzz', payload.level <> 'warn', payload.id = 42, payload.id > 100, payload.status = 1 FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(true), json!(false), json!(true), json!(false), json!(false)]);
+
+    assert!(select("SELECT payload.status + 1 FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn three_valued_logic() {
+    let data = json!({"a": true, "b": false, "n": null});
+    let res = values("SELECT payload.a AND payload.n, payload.b AND payload.n, payload.a OR payload.n, payload.b OR payload.n, NOT payload.n, payload.missing > 1 FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(null), json!(false), json!(true), json!(null), json!(null), json!(null)]);
+
+    let res = select("SELECT payload.a FROM \"/topic\" WHERE payload.missing = 1 OR payload.b", &data).unwrap();
+    assert_eq!(res.cond, Some(json!(null)));
+    assert!(!res.passed());
+
+    assert!(select("SELECT payload.a AND 1 FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn checked_arithmetic() {
+    let data = json!({"big": 18446744073709551615u64, "x": 7});
+    let res = values("SELECT 1 - 2, payload.x * 3, payload.x / 2, 8 / 2, payload.x % 4, -payload.big, payload.big + 1 FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(-1), json!(21), json!(3.5), json!(4), json!(3), json!(-18446744073709551615.0), json!(18446744073709551616.0)]);
+
+    assert!(select("SELECT payload.x / 0 FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT payload.x % 0 FROM \"/topic\"", &data).is_err());
+
+    let res = values("SELECT -1 < payload.big, 1.0 = 1 FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(true), json!(true)]);
+
+    let res = values("SELECT 2 ** 10, payload.x ** 2, 2 ** -1, 4 ** 0.5, 2 ** 3 ** 2, -2 ** 2, 2 * 3 ** 2, 2 ** 64, payload.x DIV 2, -7 DIV 2, 7.5 DIV 2, 1 + 7 DIV 2 FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(1024), json!(49), json!(0.5), json!(2.0), json!(512), json!(-4), json!(18), json!(18446744073709551616.0),
+        json!(3), json!(-3), json!(3), json!(4)]);
+
+    // `**` is one operator wherever it is, but two `*` apart are not
+    let res = values("SELECT payload.x\n    ** 2 FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(49)]);
+    assert!(select("SELECT payload.x * *2 FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT payload.x *\n* 2 FROM \"/topic\"", &data).is_err());
+
+    assert!(select("SELECT 10 ** 400 FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT 0 ** -1 FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT (-8) ** 0.5 FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT payload.x DIV 0 FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT 10 ** 300 DIV 0.000000001 FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn null_and_missing() {
+    let data = json!({"a": null, "b": 2});
+    let res = values("SELECT payload.a IS NULL, payload.x IS NULL, payload.a IS MISSING, payload.x IS MISSING, payload.b IS NOT MISSING, payload.b IS NOT NULL FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(true), json!(true), json!(false), json!(true), json!(true), json!(true)]);
+
+    let res = values("SELECT COALESCE(payload.x, payload.a, 5), IFNULL(payload.a, 'd'), NULLIF(payload.b, 2), NULLIF(payload.b, 3), payload.x + 1 IS MISSING, payload.a + 1 IS MISSING FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(5), json!("d"), json!(null), json!(2), json!(true), json!(false)]);
+
+    let res = values("SELECT COALESCE(payload.x, payload.y) IS MISSING, payload.b.c IS MISSING, payload.a.c IS MISSING FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(true), json!(true), json!(false)]);
+}
+
+#[test]
+fn case_expressions() {
+    let data = json!({"temp": 85, "mode": 2, "zero": 0});
+    let res = values("SELECT CASE WHEN payload.temp > 80 THEN 'hot' WHEN payload.temp > 50 THEN 'warm' ELSE 'cold' END, \
+        CASE payload.mode WHEN 1 THEN 'a' WHEN 2 THEN 'b' END, \
+        CASE payload.mode WHEN 3 THEN 'c' END, \
+        CASE WHEN payload.zero = 0 THEN 0 ELSE 10 / payload.zero END FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!("hot"), json!("b"), json!(null), json!(0)]);
+
+    assert!(select("SELECT CASE WHEN payload.zero = 1 THEN 0 ELSE 10 / payload.zero END FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT CASE WHEN payload.temp THEN 0 END FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn casts() {
+    let data = json!({"id": "42", "flag": "yes", "temp": 21.7, "meta": {"a": [1, 2]}, "raw": "{\"b\": 1}"});
+    let res = values("SELECT CAST(payload.id AS INT) = 42, CAST(payload.flag AS BOOLEAN), CAST(payload.temp AS INTEGER), \
+        CAST(payload.meta AS VARCHAR), CAST(payload.raw AS JSON), CAST(payload.temp AS TEXT), CAST(payload.missing AS INT) IS MISSING FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(true), json!(true), json!(21), json!("{\"a\":[1,2]}"), json!({"b": 1}), json!("21.7"), json!(true)]);
+
+    let res = values("SELECT TRY_CAST(payload.flag AS INT), TRY_CAST(payload.id AS DOUBLE) FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(null), json!(42)]);
+
+    assert!(select("SELECT CAST(payload.flag AS INT) FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT CAST(payload.meta AS INT) FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn in_list_and_between() {
+    let data = json!({"region": "eu", "temp": 25, "n": null});
+    let res = values("SELECT payload.region IN ('eu', 'us'), payload.region NOT IN ('eu', 'us'), payload.temp IN (1, 25.0), \
+        payload.temp BETWEEN 10 AND 40, payload.temp NOT BETWEEN 10 AND 40, payload.temp IN (1, payload.n), payload.temp NOT IN (1, payload.n) FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(true), json!(false), json!(true), json!(true), json!(false), json!(null), json!(null)]);
+
+    let res = foreach("FOREACH payload.items AS \"item\" RETURN item WHEN item BETWEEN 'b' AND 'c' FROM \"/topic\"", &json!({"items": ["a", "b", "c", "d"]}));
+    assert_eq!(res, vec![vec![json!("b")], vec![json!("c")]]);
+}
+
+#[test]
+fn pattern_matching() {
+    let data = json!({"name": "Sensor_12", "path": "50%_off", "n": null});
+    let res = values("SELECT payload.name LIKE 'Sensor%', payload.name LIKE 'sensor%', payload.name ILIKE 'sensor\\_1_', payload.name NOT LIKE '%12', \
+        payload.path LIKE '50!%!_%' ESCAPE '!', payload.name SIMILAR TO '(Sensor|Probe)_[0-9]+', payload.n LIKE '%' FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(true), json!(false), json!(true), json!(false), json!(true), json!(true), json!(null)]);
+
+    let res = values("SELECT REGEXP_LIKE(payload.name, '[0-9]+'), REGEXP_EXTRACT(payload.name, '_([0-9]+)', 1), REGEXP_EXTRACT(payload.name, 'x'), \
+        REGEXP_REPLACE(payload.name, '[0-9]', '#'), REGEXP_LIKE(payload.name, payload.path) FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(true), json!("12"), json!(null), json!("Sensor_##"), json!(false)]);
+
+    assert!(select("SELECT REGEXP_LIKE(payload.name, '(') FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT payload.name LIKE 5 FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn array_accessors() {
+    let data = json!({"data": {"readings": [10, 20, 30, 40], "sensors": [{"id": "a"}, {"id": "b"}]}, "n": null});
+    let res = values("SELECT payload.data.readings[0], payload.data.readings[-1], payload.data.readings[1:3], payload.data.readings[:2], \
+        payload.data.readings[-2:], payload.data.sensors[1].id, payload.data['sensors'][0]['id'], payload.data.readings[9] IS MISSING, payload.n[0] FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(10), json!(40), json!([20, 30]), json!([10, 20]), json!([30, 40]), json!("b"), json!("a"), json!(true), json!(null)]);
+
+    let res = values("SELECT payload.data.readings[1:3][0], CAST('[1, 2]' AS JSON)[1] FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(20), json!(2)]);
+
+    let res = foreach("FOREACH payload.data.sensors AS \"s\" RETURN s.id WHEN s.id = 'b' FROM \"/topic\"", &data);
+    assert_eq!(res, vec![vec![json!("b")]]);
+
+    assert!(select("SELECT payload.data.readings[payload.n] FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn path_queries() {
+    let data = json!({"items": [{"id": 1, "sub": {"id": 3}}, {"id": 2}, {"name": "x"}], "meta": {"a": 1, "b": 2}, "n": null});
+    let res = values("SELECT payload.items[*].id, payload..id, payload.meta.*, payload.items[0:2][*] IS NOT MISSING, payload.n[*], payload.x[*] FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!([1, 2]), json!([1, 3, 2]), json!([1, 2]), json!(true), json!([]), json!([])]);
+
+    let res = values("SELECT payload.items[1:]..id, payload.items..id, LENGTH(payload.items[*]) FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!([2]), json!([1, 3, 2]), json!(3)]);
+}
+
+#[test]
+fn literals() {
+    let data = json!({"active": true, "a": 1, "n": null});
+    let res = values("SELECT payload.active = TRUE, FALSE, NULL AS x, [1, 'b', -2.5, NULL], {'k': [1, 2], \"q\": {}}, 1e3 FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(true), json!(false), json!(null), json!([1, "b", -2.5, null]), json!({"k": [1, 2], "q": {}}), json!(1000.0)]);
+
+    let res = values("SELECT [payload.a, payload.x, payload.n], {'a': payload.a, 'x': payload.x}, LENGTH([payload.a]), ARRAY[1, 2][1] FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!([1, null, null]), json!({"a": 1}), json!(1), json!(2)]);
+
+    let res = foreach("FOREACH payload.items AS \"item\" RETURN item WHEN item.on = TRUE FROM \"/topic\"", &json!({"items": [{"on": true}, {"on": false}]}));
+    assert_eq!(res, vec![vec![json!({"on": true})]]);
+
+    assert!(select("SELECT OBJECT_CONSTRUCT('a') FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT OBJECT_CONSTRUCT(payload.a, 1) FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn timestamps_and_intervals() {
+    let data = json!({"ts": "2024-03-15T10:20:30.5Z", "ms": 1710498030500u64, "local": "2024-03-15 10:20:30"});
+    let res = values("SELECT CAST(payload.ts AS TIMESTAMP), CAST(payload.ms AS TIMESTAMP) = CAST(payload.ts AS TIMESTAMP), \
+        payload.ts > TIMESTAMP '2024-03-15T10:00:00Z', payload.ts + INTERVAL '1 hour 30 minutes', CAST(payload.local AS TIMESTAMP) - TIMESTAMP '2024-03-14 10:20:30', \
+        INTERVAL '5' MINUTE * 2, CAST(CAST(payload.ts AS TIMESTAMP) AS BIGINT) FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!("2024-03-15T10:20:30.500Z"), json!(true), json!(true), json!("2024-03-15T11:50:30.500Z"), json!("1 day"), json!("10 minutes"), json!(1710498030500u64)]);
+
+    let res = values("SELECT DATE_TRUNC('hour', payload.ts), DATE_TRUNC('month', payload.ms), EXTRACT(YEAR FROM payload.ts), EXTRACT(SECOND FROM payload.ts), \
+        DATE_PART('dow', payload.ts), FORMAT_TIMESTAMP('%Y/%m/%d %H:%M', payload.ts, 'Europe/Paris'), payload.ts AT TIME ZONE '+02:00', \
+        TIMESTAMP '2024-01-31' + INTERVAL '1 month' FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!("2024-03-15T10:00:00Z"), json!("2024-03-01T00:00:00Z"), json!(2024), json!(30.5), json!(5), json!("2024/03/15 11:20"),
+        json!("2024-03-15T12:20:30.500+02:00"), json!("2024-02-29T00:00:00Z")]);
+
+    let res = values("SELECT NOW() > TIMESTAMP '2024-01-01', INTERVAL '1 day' > INTERVAL '23 hours', -INTERVAL '2 seconds', CAST('90 minutes' AS INTERVAL) FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(true), json!(true), json!("-2 seconds"), json!("1 hour 30 minutes")]);
+
+    assert!(select("SELECT CAST('yesterday' AS TIMESTAMP) FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT DATE_TRUNC('fortnight', payload.ts) FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT FORMAT_TIMESTAMP('%Q', payload.ts) FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT INTERVAL '1 hour' + 'soon' FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn math_functions() {
+    let data = json!({"t": -2.5, "n": 1250, "u": 18446744073709551615u64});
+    let res = values("SELECT ABS(payload.t), ROUND(payload.t), ROUND(3.14159, 2), ROUND(payload.n, -2), TRUNC(payload.t), FLOOR(payload.t), CEIL(2.1), \
+        SQRT(16), SQRT(2) > 1.414, POWER(2, 10), SIGN(payload.t), ROUND(payload.u, -1) FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(2.5), json!(-3), json!(3.14), json!(1300), json!(-2), json!(-3), json!(3), json!(4), json!(true), json!(1024), json!(-1),
+        json!(18446744073709551620.0)]);
+
+    let res = values("SELECT EXP(0), LN(1), LOG10(1000), DEGREES(PI()), ROUND(SIN(PI() / 2), 6), ATAN2(1, 1) = PI() / 4, GREATEST(1, 2.5, payload.x, -3), \
+        LEAST(payload.n, payload.t), GREATEST(payload.x), WIDTH_BUCKET(5.35, 0.024, 10.06, 5), WIDTH_BUCKET(payload.n, 0, 1000, 4), \
+        WIDTH_BUCKET(2, 10, 0, 5), CLAMP(payload.n, 0, 100), CLAMP(payload.t, 0, 100) FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(1.0), json!(0.0), json!(3.0), json!(180.0), json!(1.0), json!(true), json!(2.5), json!(-2.5), json!(null), json!(3),
+        json!(5), json!(5), json!(100), json!(0)]);
+
+    assert!(select("SELECT LN(0) FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT SQRT(payload.t) FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT ASIN(2) FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT ROUND(payload.t, 0.5) FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT WIDTH_BUCKET(1, 0, 10, 0) FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT CLAMP(1, 10, 0) FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT EXP(1000) FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn bitwise_operators() {
+    let data = json!({"status": 45, "neg": -8, "f": 1.5});
+    let res = values("SELECT payload.status & 12, payload.status | 2, payload.status ^ 1, ~payload.status, 1 << 4, payload.status >> 2, \
+        payload.neg >> 1, payload.status & 240 = 0, BIT_COUNT(payload.status), BIT_COUNT(payload.neg), GET_BIT(payload.status, 2), GET_BIT(payload.status, 1), \
+        1 + 2 << 1 FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(12), json!(47), json!(44), json!(-46), json!(16), json!(11), json!(-4), json!(false), json!(4), json!(61), json!(1), json!(0), json!(6)]);
+
+    assert!(select("SELECT payload.f & 1 FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT ~payload.f FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT 1 << 64 FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT 1 >> -1 FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT GET_BIT(payload.status, 64) FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn foreach_aggregates() {
+    let data = json!({"readings": [
+        {"v": 2, "unit": "c", "at": "2024-03-15T10:00:00Z"}, {"v": 4, "unit": "c", "at": "2024-03-15T10:05:00Z"},
+        {"v": 6.5, "unit": "f", "at": "2024-03-15T09:55:00Z"}, {"unit": "c"}, {"v": null, "unit": null}
+    ]});
+
+    let res = select("FOREACH payload.readings AS \"r\" RETURN COUNT(*) AS n, COUNT(r.v), SUM(r.v), AVG(r.v), MIN(r.v), MAX(r.unit), \
+        ARRAY_AGG(r.unit), STRING_AGG(r.unit, ',') FROM \"/topic\"", &data).unwrap();
+    assert_eq!(res.result.iter().map(|x| x.1.clone()).collect::<Vec<_>>(), vec![json!(5), json!(3), json!(12.5), json!(12.5 / 3.0), json!(2), json!("f"),
+        json!(["c", "c", "f", "c", null]), json!("c,c,f,c")]);
+    assert_eq!(res.result[0].0, "n");
+    assert_eq!(res.cond, None);
+
+    let res = values("FOREACH payload.readings AS \"r\" RETURN AVG(r.v) AS avg, ROUND(avg * 10) / 10, MAX(r.v) - MIN(r.v), MIN(CAST(r.at AS TIMESTAMP)) \
+        WHEN r.v > 3 FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(5.25), json!(5.3), json!(2.5), json!("2024-03-15T09:55:00Z")]);
+
+    let res = select("FOREACH payload.readings AS \"r\" RETURN COUNT(*), SUM(r.v), ARRAY_AGG(r.v) WHEN r.v > 100 FROM \"/topic\" WHERE payload.site = 1", &data).unwrap();
+    assert_eq!(res.result.iter().map(|x| x.1.clone()).collect::<Vec<_>>(), vec![json!(0), json!(null), json!(null)]);
+    assert_eq!(res.cond, Some(json!(null)));
+
+    let res = values("FOREACH payload.readings AS \"r\" RETURN SUM(r.v) WHEN r.unit = 'c' FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(6)]);
+
+    assert!(select("FOREACH payload.readings AS \"r\" RETURN r.v, COUNT(*) FROM \"/topic\"", &data).is_err());
+    assert!(select("FOREACH payload.readings AS \"r\" RETURN COUNT(*) WHEN COUNT(*) > 1 FROM \"/topic\"", &data).is_err());
+    assert!(select("FOREACH payload.readings AS \"r\" RETURN SUM(COUNT(*)) FROM \"/topic\"", &data).is_err());
+    assert!(select("FOREACH payload.readings AS \"r\" RETURN SUM(r.unit) FROM \"/topic\"", &data).is_err());
+    assert!(select("FOREACH payload.readings AS \"r\" RETURN MAX(r) FROM \"/topic\"", &json!({"readings": [1, "a"]})).is_err());
+    assert!(select("SELECT COUNT(*) FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn group_by_and_having() {
+    let data = json!({"order": 7, "items": [
+        {"category": "fruit", "amount": 3}, {"category": "veg", "amount": 2.5}, {"category": "fruit", "amount": 4},
+        {"category": "dairy", "amount": 1}, {"amount": 10}, {"category": "veg", "amount": 1.5}
+    ]});
+
+    let res = foreach("FOREACH payload.items AS \"item\" RETURN item.category, SUM(item.amount) AS total, COUNT(*) FROM \"/topic\" GROUP BY item.category", &data);
+    assert_eq!(res, vec![vec![json!("fruit"), json!(7), json!(2)], vec![json!("veg"), json!(4.0), json!(2)], vec![json!("dairy"), json!(1), json!(1)],
+        vec![json!(null), json!(10), json!(1)]]);
+
+    let res = foreach("FOREACH payload.items AS \"item\" RETURN UPPER(item.category) AS c, MAX(item.amount) - MIN(item.amount) WHEN item.amount < 10 \
+        FROM \"/topic\" WHERE payload.order = 7 GROUP BY item.category HAVING COUNT(*) > 1 AND c <> 'VEG'", &data);
+    assert_eq!(res, vec![vec![json!("FRUIT"), json!(1)]]);
+
+    let res = foreach("FOREACH payload.items AS \"item\" RETURN item.amount > 2 AS big, COUNT(*) FROM \"/topic\" GROUP BY item.amount > 2", &data);
+    assert_eq!(res, vec![vec![json!(true), json!(4)], vec![json!(false), json!(2)]]);
+
+    let res = foreach("FOREACH payload.items AS \"item\" RETURN COUNT(*) FROM \"/topic\" WHERE payload.order = 8 GROUP BY item.category", &data);
+    assert_eq!(res, Vec::<Vec<serde_json::Value>>::new());
+
+    // Keys equal by `=` share a group, MISSING and NULL don't
+    let data = json!({"items": [{"k": 2}, {"k": 2.0}, {"k": -0.0}, {"k": 0}, {"k": 2.5}, {"k": {"a": 1, "b": 2}}, {"k": {"b": 2.0, "a": 1}}]});
+    let res = foreach("FOREACH payload.items AS \"i\" RETURN i.k, COUNT(*) FROM \"/topic\" GROUP BY i.k", &data);
+    assert_eq!(res, vec![vec![json!(2), json!(2)], vec![json!(-0.0), json!(2)], vec![json!(2.5), json!(1)], vec![json!({"a": 1, "b": 2}), json!(2)]]);
+    let data = json!({"items": [{"k": null}, {}, {"k": null}, {"j": 1}, {}]});
+    let res = foreach("FOREACH payload.items AS \"i\" RETURN i.k IS MISSING, COUNT(*) FROM \"/topic\" GROUP BY i.k", &data);
+    assert_eq!(res, vec![vec![json!(false), json!(2)], vec![json!(true), json!(3)]]);
+
+    assert!(select("FOREACH payload.items AS \"item\" RETURN item.amount, COUNT(*) FROM \"/topic\" GROUP BY item.category", &data).is_err());
+    assert!(select("FOREACH payload.items AS \"item\" RETURN COUNT(*) FROM \"/topic\" GROUP BY COUNT(*)", &data).is_err());
+    assert!(select("FOREACH payload.items AS \"item\" RETURN COUNT(*) FROM \"/topic\" HAVING COUNT(*) > 1", &data).is_err());
+    assert!(select("FOREACH payload.items AS \"item\" RETURN COUNT(*) FROM \"/topic\" GROUP BY item.category HAVING item.amount > 1", &data).is_err());
+    assert!(select("SELECT payload.order FROM \"/topic\" GROUP BY payload.order", &data).is_err());
+}
+
+#[test]
+fn order_by_limit_offset() {
+    let data = json!({"readings": [
+        {"id": 1, "v": 20.5}, {"id": 2, "v": null}, {"id": 3, "v": 31}, {"id": 4, "v": "n/a"},
+        {"id": 5, "v": 12}, {"id": 6}, {"id": 7, "v": 31}, {"id": 8, "v": [1]}, {"id": 9, "v": true}
+    ]});
+
+    let res = foreach("FOREACH payload.readings AS \"r\" RETURN r.id FROM \"/topic\" ORDER BY r.v", &data);
+    assert_eq!(res, vec![vec![json!(9)], vec![json!(5)], vec![json!(1)], vec![json!(3)], vec![json!(7)], vec![json!(4)],
+        vec![json!(8)], vec![json!(2)], vec![json!(6)]]);
+
+    let res = foreach("FOREACH payload.readings AS \"r\" RETURN r.id, r.v WHEN r.id % 2 = 1 AND r.id < 9 FROM \"/topic\" ORDER BY r.v DESC, r.id DESC LIMIT 3", &data);
+    assert_eq!(res, vec![vec![json!(7), json!(31)], vec![json!(3), json!(31)], vec![json!(1), json!(20.5)]]);
+
+    let res = foreach("FOREACH payload.readings AS \"r\" RETURN r.id FROM \"/topic\" ORDER BY r.v DESC NULLS LAST, r.id OFFSET 1 LIMIT 2", &data);
+    assert_eq!(res, vec![vec![json!(4)], vec![json!(3)]]);
+
+    let res = foreach("FOREACH payload.readings AS \"r\" RETURN r.id AS id FROM \"/topic\" ORDER BY r.v IS NULL NULLS FIRST, id DESC LIMIT 2 OFFSET 7", &data);
+    assert_eq!(res, vec![vec![json!(6)], vec![json!(2)]]);
+
+    let data = json!({"items": [{"k": "b", "n": 1}, {"k": "a", "n": 5}, {"k": "b", "n": 2}, {"k": "c", "n": 4}]});
+    let res = foreach("FOREACH payload.items AS \"i\" RETURN i.k, SUM(i.n) AS total FROM \"/topic\" GROUP BY i.k ORDER BY total DESC LIMIT 2", &data);
+    assert_eq!(res, vec![vec![json!("a"), json!(5)], vec![json!("c"), json!(4)]]);
+
+    // Intervals sort by length as `<` compares them, a month being 30 days
+    let data = json!({"spans": [{"id": 1, "d": "1 month"}, {"id": 2, "d": "29 days"}, {"id": 3, "d": "30 days"}, {"id": 4, "d": "31 days"}]});
+    let res = foreach("FOREACH payload.spans AS \"s\" RETURN s.id FROM \"/topic\" ORDER BY CAST(s.d AS INTERVAL)", &data);
+    assert_eq!(res, vec![vec![json!(2)], vec![json!(3)], vec![json!(1)], vec![json!(4)]]);
+
+    assert!(select("FOREACH payload.items AS \"i\" RETURN i.k FROM \"/topic\" LIMIT -1", &data).is_err());
+    assert!(select("FOREACH payload.items AS \"i\" RETURN i.k FROM \"/topic\" LIMIT payload.n", &data).is_err());
+    assert!(select("FOREACH payload.items AS \"i\" RETURN i.k, SUM(i.n) FROM \"/topic\" GROUP BY i.k ORDER BY i.n", &data).is_err());
+}
+
+#[test]
+fn distinct() {
+    let data = json!({"tags": ["a", "b", "a", {"x": 1, "y": [2]}, "c", {"y": [2.0], "x": 1}, "b", null, null]});
+
+    let res = foreach("FOREACH payload.tags AS \"t\" RETURN DISTINCT t FROM \"/topic\"", &data);
+    assert_eq!(res, vec![vec![json!("a")], vec![json!("b")], vec![json!({"x": 1, "y": [2]})], vec![json!("c")], vec![json!(null)]]);
+
+    let res = foreach("FOREACH payload.tags AS \"t\" RETURN DISTINCT t WHEN t IN ('a', 'b', 'c') FROM \"/topic\" ORDER BY t DESC LIMIT 2", &data);
+    assert_eq!(res, vec![vec![json!("c")], vec![json!("b")]]);
+
+    let res = foreach("FOREACH payload.tags AS \"t\" RETURN t FROM \"/topic\"", &data);
+    assert_eq!(res.len(), 9);
+
+    let res = values("FOREACH payload.tags AS \"t\" RETURN COUNT(DISTINCT t), COUNT(t), ARRAY_AGG(DISTINCT t) FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(4), json!(7), json!(["a", "b", {"x": 1, "y": [2]}, "c", null])]);
+
+    let data = json!({"items": [{"k": "a", "n": 1}, {"k": "a", "n": 1}, {"k": "b", "n": 2}, {"k": "a", "n": 3}]});
+    let res = foreach("FOREACH payload.items AS \"i\" RETURN i.k, COUNT(DISTINCT i.n), SUM(DISTINCT i.n) FROM \"/topic\" GROUP BY i.k", &data);
+    assert_eq!(res, vec![vec![json!("a"), json!(2), json!(4)], vec![json!("b"), json!(1), json!(2)]]);
+
+    let data = json!({"n": [1, 1.0, 2, 2.5, 2.0, 1]});
+    let res = foreach("FOREACH payload.n AS \"n\" RETURN DISTINCT n FROM \"/topic\"", &data);
+    assert_eq!(res, vec![vec![json!(1)], vec![json!(2)], vec![json!(2.5)]]);
+    let res = foreach("FOREACH payload.n AS \"n\" RETURN\n    DISTINCT n FROM \"/topic\"", &data);
+    assert_eq!(res, vec![vec![json!(1)], vec![json!(2)], vec![json!(2.5)]]);
+    let res = values("FOREACH payload.n AS \"n\" RETURN SUM(DISTINCT n), COUNT(DISTINCT n) FROM \"/topic\"", &data);
+    assert_eq!(res, vec![json!(5.5), json!(3)]);
+    let res = foreach("FOREACH payload.n AS \"n\" RETURN ALL n * 2 AS d FROM \"/topic\" WHERE TRUE", &data);
+    assert_eq!(res.len(), 6);
+
+    assert!(select("FOREACH payload.n AS \"n\" RETURN n, DISTINCT n FROM \"/topic\"", &data).is_err());
+    assert!(select("SELECT UPPER(DISTINCT payload.k) FROM \"/topic\"", &data).is_err());
+    assert!(select("FOREACH payload.items AS \"i\" RETURN DISTINCT ON (i.k) i.n FROM \"/topic\"", &data).is_err());
+}
+
+#[test]
+fn tumbling_and_hopping_windows() {
+    let messages = vec![
+        json!({"ts": "2024-01-01T10:00:05Z", "sensor": "a", "v": 1}),
+        json!({"ts": "2024-01-01T10:00:40Z", "sensor": "b", "v": 3}),
+        json!({"ts": "2024-01-01T10:01:10Z", "sensor": "a", "v": 5}),
+        json!({"ts": "2024-01-01T10:00:50Z", "sensor": "a", "v": 100}),
+        json!({"ts": "2024-01-01T10:01:30Z", "sensor": "a", "v": 7}),
+        json!({"ts": "2024-01-01T10:02:00Z", "sensor": "b", "v": 1}),
+    ];
+
+    let res = stream("SELECT WINDOW_START() AS s, WINDOW_END(), COUNT(*), SUM(payload.v) FROM \"/topic\" \
+        GROUP BY TUMBLE(payload.ts, INTERVAL '1 minute')", &messages);
+    assert_eq!(res, vec![vec![], vec![],
+        vec![vec![vec![json!("2024-01-01T10:00:00Z"), json!("2024-01-01T10:01:00Z"), json!(2), json!(4)]]],
+        vec![], vec![],
+        vec![vec![vec![json!("2024-01-01T10:01:00Z"), json!("2024-01-01T10:02:00Z"), json!(2), json!(12)]]],
+        vec![vec![vec![json!("2024-01-01T10:02:00Z"), json!("2024-01-01T10:03:00Z"), json!(1), json!(1)]]]]);
+
+    let res = stream("SELECT payload.sensor, MAX(payload.v) FROM \"/topic\" WHERE payload.v < 50 \
+        GROUP BY TUMBLE(payload.ts, '2 minutes'), payload.sensor HAVING COUNT(*) > 1", &messages);
+    assert_eq!(res.concat(), vec![vec![vec![json!("a"), json!(7)]], vec![]]);
+
+    let res = stream("SELECT WINDOW_START(), ARRAY_AGG(payload.v) FROM \"/topic\" \
+        GROUP BY HOP(payload.ts, INTERVAL '30 seconds', INTERVAL '1 minute')", &messages);
+    assert_eq!(res[1], vec![vec![vec![json!("2024-01-01T09:59:30Z"), json!([1])]]]);
+    assert_eq!(res[2], vec![vec![vec![json!("2024-01-01T10:00:00Z"), json!([1, 3])]]]);
+    assert_eq!(res[4], vec![vec![vec![json!("2024-01-01T10:00:30Z"), json!([3, 5, 100])]]]);
+    assert_eq!(res[5], vec![vec![vec![json!("2024-01-01T10:01:00Z"), json!([5, 7])]]]);
+    assert_eq!(res[6], vec![vec![vec![json!("2024-01-01T10:01:30Z"), json!([7, 1])]], vec![vec![json!("2024-01-01T10:02:00Z"), json!([1])]]]);
+
+    let data = json!({"ts": "2024-01-01T10:00:05Z", "v": 1});
+    let mut query = parse_stream("SELECT COUNT(*) FROM \"/topic\" GROUP BY TUMBLE(payload.v, INTERVAL '1 minute')".to_string()).unwrap().remove(0);
+    assert!(query.push(&json!({"v": true}).into()).is_err());
+    assert!(parse_and_execute("SELECT COUNT(*) FROM \"/topic\" GROUP BY TUMBLE(payload.ts, INTERVAL '1 minute')".to_string(), &data).unwrap()[0].is_err());
+    assert!(select("SELECT COUNT(*) FROM \"/topic\" GROUP BY TUMBLE(payload.ts, INTERVAL '1 month')", &data).is_err());
+    assert!(select("SELECT COUNT(*) FROM \"/topic\" GROUP BY TUMBLE(payload.ts, INTERVAL '1 minute'), HOP(payload.ts, '1 minute', '2 minutes')", &data).is_err());
+    assert!(select("SELECT payload.v, COUNT(*) FROM \"/topic\" GROUP BY TUMBLE(payload.ts, INTERVAL '1 minute')", &data).is_err());
+    assert!(select("SELECT WINDOW_START() FROM \"/topic\"", &data).is_err());
+    assert!(select("FOREACH payload.items AS \"i\" RETURN COUNT(*) FROM \"/topic\" GROUP BY TUMBLE(i.ts, INTERVAL '1 minute')", &data).is_err());
+}
+
+#[test]
+fn session_windows() {
+    let messages = vec![
+        json!({"ts": "2024-01-01T10:00:00Z", "device": "a", "v": 1}),
+        json!({"ts": "2024-01-01T10:00:10Z", "device": "b", "v": 2}),
+        json!({"ts": "2024-01-01T10:00:20Z", "device": "a", "v": 3}),
+        json!({"ts": "2024-01-01T10:00:45Z", "device": "b", "v": 4}),
+        json!({"ts": "2024-01-01T10:00:55Z", "device": "a", "v": 5}),
+        json!({"ts": "2024-01-01T10:01:30Z", "device": "b", "v": 6}),
+    ];
+
+    let sql = "SELECT payload.device, WINDOW_START(), WINDOW_END(), SUM(payload.v) FROM \"/topic\" \
+        GROUP BY SESSION(payload.ts, INTERVAL '30 seconds'), payload.device";
+    let res = stream(sql, &messages);
+    assert_eq!(res, vec![vec![], vec![], vec![],
+        vec![vec![vec![json!("b"), json!("2024-01-01T10:00:10Z"), json!("2024-01-01T10:00:40Z"), json!(2)]]],
+        vec![vec![vec![json!("a"), json!("2024-01-01T10:00:00Z"), json!("2024-01-01T10:00:50Z"), json!(4)]]],
+        vec![
+            vec![vec![json!("b"), json!("2024-01-01T10:00:45Z"), json!("2024-01-01T10:01:15Z"), json!(4)]],
+            vec![vec![json!("a"), json!("2024-01-01T10:00:55Z"), json!("2024-01-01T10:01:25Z"), json!(5)]],
+        ],
+        vec![vec![vec![json!("b"), json!("2024-01-01T10:01:30Z"), json!("2024-01-01T10:02:00Z"), json!(6)]]]]);
+
+    let mut query = parse_stream(sql.to_string()).unwrap().remove(0);
+    messages.iter().for_each(|x| { query.push(&x.clone().into()).unwrap(); });
+    assert_eq!(query.window_count(), 1);
+    query.push(&json!({"ts": "2024-01-01T10:05:00Z", "device": "c", "v": 0}).into()).unwrap();
+    assert_eq!(query.window_count(), 1);
+    query.finish().unwrap();
+    assert_eq!(query.window_count(), 0);
+
+    let res = stream("SELECT COUNT(*), WINDOW_END() FROM \"/topic\" GROUP BY SESSION(payload.ts, '30 seconds')", &messages);
+    assert_eq!(res.concat(), vec![vec![vec![json!(5), json!("2024-01-01T10:01:25Z")]], vec![vec![json!(1), json!("2024-01-01T10:02:00Z")]]]);
+
+    // A message arriving out of order between two sessions of a key joins them
+    let mut query = parse_stream(sql.to_string()).unwrap().remove(0)
+        .with_lateness(Lateness { out_of_orderness: 60_000_000, ..Default::default() }).unwrap();
+    for (ts, v) in [("10:00:00", 1), ("10:01:00", 2), ("10:00:30", 3)] {
+        assert!(query.push(&json!({"ts": format!("2024-01-01T{}Z", ts), "device": "a", "v": v}).into()).unwrap().is_empty());
+    }
+    assert_eq!(query.window_count(), 1);
+    let res = query.finish().unwrap().into_iter().map(|x| match x {
+        StreamOutput::Result(result) => rows(result),
+        other => panic!("Expected result, got {:?}", other),
+    }).collect::<Vec<_>>();
+    assert_eq!(res, vec![vec![vec![json!("a"), json!("2024-01-01T10:00:00Z"), json!("2024-01-01T10:01:30Z"), json!(6)]]]);
+}
+
+#[test]
+fn watermarks_and_late_messages() {
+    let messages = vec![
+        json!({"ts": "2024-01-01T10:00:10Z", "v": 1}),
+        json!({"ts": "2024-01-01T10:01:05Z", "v": 2}),
+        json!({"ts": "2024-01-01T10:00:50Z", "v": 3}),
+        json!({"ts": "2024-01-01T10:01:40Z", "v": 4}),
+        json!({"ts": "2024-01-01T10:00:20Z", "v": 5}),
+        json!({"ts": "2024-01-01T10:03:00Z", "v": 6}),
+        json!({"ts": "2024-01-01T10:00:30Z", "v": 7}),
+    ];
+    let sql = "SELECT WINDOW_START(), ARRAY_AGG(payload.v) FROM \"/topic\" GROUP BY TUMBLE(payload.ts, INTERVAL '1 minute')";
+    let run = |lateness: Lateness| {
+        let mut query = parse_stream(sql.to_string()).unwrap().remove(0).with_lateness(lateness).unwrap();
+        let outputs = messages.iter().map(|x| query.push(&x.clone().into()).unwrap()).collect::<Vec<_>>();
+        (outputs, query.window_count())
+    };
+    let values = |output: &StreamOutput| match output {
+        StreamOutput::Result(QueryResult::Simple(simple)) | StreamOutput::Correction(QueryResult::Simple(simple)) => {
+            simple.result.iter().map(|x| x.1.clone()).collect::<Vec<_>>()
+        },
+        other => panic!("Expected simple result, got {:?}", other),
+    };
+
+    // Messages up to 30 seconds out of order are on time, later ones are dropped
+    let (outputs, _) = run(Lateness { out_of_orderness: 30_000_000, ..Default::default() });
+    assert_eq!(outputs.iter().map(|x| x.len()).collect::<Vec<_>>(), vec![0, 0, 0, 1, 0, 1, 0]);
+    assert_eq!(values(&outputs[3][0]), vec![json!("2024-01-01T10:00:00Z"), json!([1, 3])]);
+    assert_eq!(values(&outputs[5][0]), vec![json!("2024-01-01T10:01:00Z"), json!([2, 4])]);
+
+    let (outputs, _) = run(Lateness { policy: LatePolicy::SideOutput, ..Default::default() });
+    assert!(matches!(&outputs[2][..], [StreamOutput::Late(x)] if *x == messages[2]));
+    assert!(matches!(&outputs[6][..], [StreamOutput::Late(x)] if *x == messages[6]));
+
+    // A hopping window still open takes a message the earlier one closed for, it
+    // is only set aside once all of its windows closed
+    let hop = "SELECT WINDOW_START(), ARRAY_AGG(payload.v) FROM \"/topic\" GROUP BY HOP(payload.ts, INTERVAL '30 seconds', INTERVAL '1 minute')";
+    let mut query = parse_stream(hop.to_string()).unwrap().remove(0).with_lateness(Lateness { policy: LatePolicy::SideOutput, ..Default::default() }).unwrap();
+    let hop_messages = vec![
+        json!({"ts": "2024-01-01T10:00:10Z", "v": 1}),
+        json!({"ts": "2024-01-01T10:00:45Z", "v": 2}),
+        json!({"ts": "2024-01-01T10:00:20Z", "v": 3}),
+        json!({"ts": "2024-01-01T09:59:50Z", "v": 4}),
+    ];
+    let outputs = hop_messages.iter().map(|x| query.push(&x.clone().into()).unwrap()).collect::<Vec<_>>();
+    assert_eq!(values(&outputs[1][0]), vec![json!("2024-01-01T09:59:30Z"), json!([1])]);
+    assert!(outputs[2].is_empty());
+    assert!(matches!(&outputs[3][..], [StreamOutput::Late(x)] if *x == hop_messages[3]));
+    let res = query.finish().unwrap();
+    assert_eq!(values(&res[0]), vec![json!("2024-01-01T10:00:00Z"), json!([1, 2, 3])]);
+
+    // Closed windows take late messages for another 90 seconds of event time
+    let (outputs, window_count) = run(Lateness { out_of_orderness: 0, allowed_lateness: 90_000_000, policy: LatePolicy::Correct });
+    assert!(matches!(&outputs[1][..], [StreamOutput::Result(_)]));
+    assert!(matches!(&outputs[2][..], [StreamOutput::Correction(_)]));
+    assert_eq!(values(&outputs[2][0]), vec![json!("2024-01-01T10:00:00Z"), json!([1, 3])]);
+    assert_eq!(values(&outputs[4][0]), vec![json!("2024-01-01T10:00:00Z"), json!([1, 3, 5])]);
+    assert!(matches!(&outputs[5][..], [StreamOutput::Result(_)]));
+    assert!(outputs[6].is_empty());
+    assert_eq!(window_count, 2);
+
+    assert!(parse_stream(sql.to_string()).unwrap().remove(0).with_lateness(Lateness { out_of_orderness: -1, ..Default::default() }).is_err());
+    assert!(parse_stream(sql.to_string()).unwrap().remove(0).with_lateness(Lateness { allowed_lateness: 1, policy: LatePolicy::SideOutput, ..Default::default() }).is_err());
+}
+
+#[test]
+fn compiled_queries() {
+    fn shared<T: Send + Sync>(_: &T) {}
+
+    let mut queries = compile("SELECT payload.v * 2 AS v FROM \"/topic\" WHERE payload.v > 1; FOREACH payload.items AS \"item\" RETURN item FROM \"/topic\" ORDER BY item DESC LIMIT 2".to_string()).unwrap();
+    let (select, items) = (queries.remove(0), queries.remove(0));
+    shared(&select);
+
+    // One context per thread, reused for every message it runs
+    let results = std::thread::scope(|scope| {
+        let handles = (0..4).map(|thread| {
+            let select = &select;
+            scope.spawn(move || {
+                let mut context = select.new_context();
+                (0..100).map(|i| {
+                    let simple = match select.execute(&mut context, &json!({"v": thread * 100 + i}).into()).unwrap() {
+                        QueryResult::Simple(simple) => simple,
+                        _ => panic!("Expected simple result"),
+                    };
+                    (simple.passed(), simple.result[0].1.clone())
+                }).collect::<Vec<_>>()
+            })
+        }).collect::<Vec<_>>();
+        handles.into_iter().map(|x| x.join().unwrap()).collect::<Vec<_>>()
+    });
+    assert_eq!(results[0][1], (false, json!(2)));
+    assert_eq!(results[0][2], (true, json!(4)));
+    assert_eq!(results[3][99], (true, json!(798)));
+
+    let mut context = items.new_context();
+    assert_eq!(rows(items.execute(&mut context, &json!({"items": [1, 3, 2]}).into()).unwrap()), vec![vec![json!(3)], vec![json!(2)]]);
+    assert_eq!(rows(items.execute(&mut context, &json!({"items": [5]}).into()).unwrap()), vec![vec![json!(5)]]);
+
+    // A context only fits the query it was made for
+    assert!(items.execute(&mut select.new_context(), &json!({"items": []}).into()).is_err());
+}
+
+#[test]
+fn topic_routing() {
+    let mut router = TopicRouter::new();
+    let sql = "SELECT payload.v FROM \"sensors/+/temp\"; SELECT payload.v FROM \"sensors/#\"; SELECT payload.v FROM \"#\"; SELECT payload.v FROM \"sensors/a/temp\"";
+    for query in parse_stream(sql.to_string()).unwrap() {
+        router.add(query).unwrap();
+    }
+    for i in 0..1000 {
+        router.add(parse_stream(format!("SELECT payload.v FROM \"devices/{}/+\"", i)).unwrap().remove(0)).unwrap();
+    }
+
+    let mut route = |topic: &str| -> Vec<usize> {
+        router.route(topic, &json!({"v": 1}).into()).unwrap().into_iter().map(|(i, output)| {
+            match output.unwrap().remove(0) {
+                StreamOutput::Result(result) => assert_eq!(rows(result), vec![vec![json!(1)]]),
+                other => panic!("Expected result, got {:?}", other),
+            }
+            i
+        }).collect()
+    };
+    assert_eq!(route("sensors/a/temp"), vec![0, 1, 2, 3]);
+    assert_eq!(route("sensors/b/humidity"), vec![1, 2]);
+    assert_eq!(route("sensors"), vec![1, 2]);
+    assert_eq!(route("devices/500/status"), vec![2, 504]);
+    assert_eq!(route("devices/500"), vec![2]);
+    // Wildcards don't match system topics
+    assert_eq!(route("$SYS/sensors"), Vec::<usize>::new());
+
+    assert!(router.route("sensors/+/temp", &json!({}).into()).is_err());
+    assert!(parse_stream("SELECT payload.v FROM \"sensors/te+mp\"".to_string()).is_err());
+    assert!(parse_stream("SELECT payload.v FROM \"#/temp\"".to_string()).is_err());
+    assert_eq!(parse_stream("SELECT payload.v FROM sensors".to_string()).unwrap()[0].topic_filter().unwrap().to_string(), "sensors");
+}
+
+#[test]
+fn topic_captures() {
+    let select = compile("SELECT device_id, payload.v FROM \"/devices/{device_id}/telemetry\" WHERE device_id != 'b'".to_string()).unwrap().remove(0);
+    let mut context = select.new_context();
+    let mut run = |topic: &str| select.execute_on(&mut context, topic, &json!({"v": 1}).into()).map(rows);
+    assert_eq!(run("/devices/a/telemetry"), Ok(vec![vec![json!("a"), json!(1)]]));
+    assert_eq!(run("/devices/b/telemetry"), Ok(vec![]));
+    assert!(run("/devices/a/status").is_err());
+    assert!(run("/devices/a").is_err());
+
+    let res = foreach("FOREACH payload.items AS \"i\" RETURN site, i FROM \"/{site}/items\"", &json!({"items": [1]}));
+    assert_eq!(res, vec![vec![serde_json::Value::Null, json!(1)]]);
+
+    let mut router = TopicRouter::new();
+    let sql = "SELECT device_id, COUNT(*) FROM \"/devices/{device_id}/+\" GROUP BY device_id, TUMBLE(payload.ts, INTERVAL '1 minute'); \
+        SELECT site, device FROM \"{site}/{device}/#\"";
+    for query in parse_stream(sql.to_string()).unwrap() {
+        router.add(query).unwrap();
+    }
+    let mut route = |topic: &str, ts: &str| router.route(topic, &json!({"ts": ts}).into()).unwrap().into_iter().flat_map(|(i, output)| {
+        output.unwrap().into_iter().map(move |x| match x {
+            StreamOutput::Result(result) => (i, rows(result)),
+            other => panic!("Expected result, got {:?}", other),
+        })
+    }).collect::<Vec<_>>();
+    assert_eq!(route("/devices/a/temp", "2024-01-01T10:00:00Z"), vec![(1, vec![vec![json!(""), json!("devices")]])]);
+    route("/devices/b/temp", "2024-01-01T10:00:10Z");
+    route("/devices/a/humidity", "2024-01-01T10:00:20Z");
+    assert_eq!(route("/devices/a/temp", "2024-01-01T10:01:00Z")[0], (0, vec![vec![json!("a"), json!(2)], vec![json!("b"), json!(1)]]));
+    assert_eq!(route("$SYS/devices/x", "2024-01-01T10:01:00Z"), vec![]);
+
+    assert!(compile("SELECT device_id, COUNT(*) FROM \"/devices/{device_id}\"".to_string()).is_err());
+    assert!(compile("SELECT payload.v AS id FROM \"/devices/{id}\"".to_string()).is_err());
+    assert!(compile("SELECT payload FROM \"/devices/{payload}\"".to_string()).is_err());
+    assert!(compile("SELECT 1 FROM \"/devices/{id}/{id}\"".to_string()).is_err());
+    assert!(compile("SELECT 1 FROM \"/devices/{1d}\"".to_string()).is_err());
+    assert!(compile("SELECT 1 FROM \"/devices/x{id}\"".to_string()).is_err());
+}
@@ -0,0 +1,303 @@
+use chrono::{format::{Item, StrftimeItems}, DateTime, Datelike, Duration, FixedOffset, Months, NaiveDate, NaiveDateTime, Offset, SecondsFormat, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+
+use crate::json_math::JsonNumber;
+
+use super::types::SQLValue;
+
+/// Instant with the offset it is displayed in, timestamps compare by instant
+pub type Timestamp = DateTime<FixedOffset>;
+
+/// Calendar months and an exact duration. Months have no fixed length so they
+/// are kept apart and only count as 30 days where a length is needed.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Interval {
+    pub months: i64,
+    pub micros: i64,
+}
+
+const MICROS_PER_SECOND: i64 = 1_000_000;
+const MICROS_PER_DAY: i64 = 86_400 * MICROS_PER_SECOND;
+const DAYS_PER_MONTH: i64 = 30;
+
+/// Interval units with their length in months or microseconds
+const INTERVAL_UNITS: &[(&[&str], i64, i64)] = &[
+    (&["microsecond", "microseconds", "us"], 0, 1),
+    (&["millisecond", "milliseconds", "ms"], 0, 1_000),
+    (&["second", "seconds", "sec", "secs", "s"], 0, MICROS_PER_SECOND),
+    (&["minute", "minutes", "min", "mins", "m"], 0, 60 * MICROS_PER_SECOND),
+    (&["hour", "hours", "h"], 0, 3_600 * MICROS_PER_SECOND),
+    (&["day", "days", "d"], 0, MICROS_PER_DAY),
+    (&["week", "weeks", "w"], 0, 7 * MICROS_PER_DAY),
+    (&["month", "months", "mon", "mons"], 1, 0),
+    (&["quarter", "quarters"], 3, 0),
+    (&["year", "years", "y"], 12, 0),
+];
+
+impl Interval {
+    /// Length with months counted as 30 days, used to compare and scale intervals
+    pub fn approx_micros(&self) -> i128 {
+        self.months as i128 * (DAYS_PER_MONTH * MICROS_PER_DAY) as i128 + self.micros as i128
+    }
+
+    pub fn checked_add(self, rhs: Interval) -> Result<Interval, String> {
+        match (self.months.checked_add(rhs.months), self.micros.checked_add(rhs.micros)) {
+            (Some(months), Some(micros)) => Ok(Interval { months, micros }),
+            _ => Err("Interval out of range".to_string()),
+        }
+    }
+
+    pub fn checked_neg(self) -> Result<Interval, String> {
+        match (self.months.checked_neg(), self.micros.checked_neg()) {
+            (Some(months), Some(micros)) => Ok(Interval { months, micros }),
+            _ => Err("Interval out of range".to_string()),
+        }
+    }
+
+    /// Whole factors keep months apart, fractional ones fold them into the duration
+    pub fn checked_mul(self, factor: JsonNumber) -> Result<Interval, String> {
+        let factor = factor.as_f64();
+        if factor.fract() == 0.0 {
+            let months = self.months as f64 * factor;
+            let micros = self.micros as f64 * factor;
+            return Ok(Interval { months: to_i64(months)?, micros: to_i64(micros)? });
+        }
+
+        Ok(Interval { months: 0, micros: to_i64(self.approx_micros() as f64 * factor)? })
+    }
+
+    pub fn checked_div(self, divisor: JsonNumber) -> Result<Interval, String> {
+        if divisor.is_zero() {
+            return Err("Division by zero in interval division".to_string());
+        }
+        Ok(Interval { months: 0, micros: to_i64(self.approx_micros() as f64 / divisor.as_f64())? })
+    }
+}
+
+fn to_i64(n: f64) -> Result<i64, String> {
+    let n = n.round();
+    if n.is_finite() && n.abs() < i64::MAX as f64 {
+        Ok(n as i64)
+    } else {
+        Err("Interval out of range".to_string())
+    }
+}
+
+/// Written with the units `parse_interval` reads, e.g. `1 month 2 days 30 minutes`
+impl std::fmt::Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let plural = |n: i64, unit: &str| format!("{} {}{}", n, unit, if n.abs() == 1 { "" } else { "s" });
+        let mut parts = Vec::<String>::new();
+
+        let (years, months) = (self.months / 12, self.months % 12);
+        let (days, rest) = (self.micros / MICROS_PER_DAY, self.micros % MICROS_PER_DAY);
+        let (hours, rest) = (rest / (3_600 * MICROS_PER_SECOND), rest % (3_600 * MICROS_PER_SECOND));
+        let (minutes, micros) = (rest / (60 * MICROS_PER_SECOND), rest % (60 * MICROS_PER_SECOND));
+
+        for (n, unit) in [(years, "year"), (months, "month"), (days, "day"), (hours, "hour"), (minutes, "minute")] {
+            if n != 0 {
+                parts.push(plural(n, unit));
+            }
+        }
+        if micros % MICROS_PER_SECOND != 0 {
+            parts.push(format!("{} seconds", micros as f64 / MICROS_PER_SECOND as f64));
+        } else if micros != 0 || parts.is_empty() {
+            parts.push(plural(micros / MICROS_PER_SECOND, "second"));
+        }
+
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// Reads `5 minutes`, `1 hour 30 minutes` or `-1.5 days`, a bare number is seconds
+pub fn parse_interval(s: &str) -> Result<Interval, String> {
+    let error = || format!("Cannot parse interval {:?}", s);
+    let words = s.split_whitespace().collect::<Vec<&str>>();
+    if words.is_empty() {
+        return Err(error());
+    }
+
+    let mut interval = Interval { months: 0, micros: 0 };
+    for pair in words.chunks(2) {
+        let n = pair[0].parse::<f64>().map_err(|_| error())?;
+        let unit = pair.get(1).map_or("second".to_string(), |x| x.to_lowercase());
+        let (_, months, micros) = INTERVAL_UNITS.iter()
+            .find(|(names, _, _)| names.contains(&unit.as_str()))
+            .ok_or_else(error)?;
+
+        let part = if *months != 0 && n.fract() == 0.0 {
+            Interval { months: to_i64(n * *months as f64)?, micros: 0 }
+        } else {
+            let micros = if *months != 0 { *months * DAYS_PER_MONTH * MICROS_PER_DAY } else { *micros };
+            Interval { months: 0, micros: to_i64(n * micros as f64)? }
+        };
+        interval = interval.checked_add(part)?;
+    }
+
+    Ok(interval)
+}
+
+/// Reads a timestamp from a timestamp value, an ISO-8601 string or epoch milliseconds
+pub fn to_timestamp(value: &SQLValue) -> Result<Timestamp, String> {
+    match value {
+        SQLValue::Timestamp(ts) => Ok(*ts),
+        SQLValue::Json(serde_json::Value::String(s)) => parse_timestamp(s),
+        SQLValue::Json(serde_json::Value::Number(n)) => from_epoch_millis(JsonNumber::from(n).as_f64()),
+        other => Err(format!("Expected a timestamp, got {}", other.clone().into_json())),
+    }
+}
+
+/// ISO-8601 with `T` or a space, a missing offset is UTC and a date alone is midnight
+pub fn parse_timestamp(s: &str) -> Result<Timestamp, String> {
+    let s = s.trim();
+    if let Ok(ts) = DateTime::parse_from_rfc3339(s) {
+        return Ok(ts);
+    }
+
+    for format in ["%Y-%m-%dT%H:%M:%S%.f%#z", "%Y-%m-%d %H:%M:%S%.f%#z"] {
+        if let Ok(ts) = DateTime::parse_from_str(s, format) {
+            return Ok(ts);
+        }
+    }
+
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M", "%Y-%m-%d %H:%M"] {
+        if let Ok(ts) = NaiveDateTime::parse_from_str(s, format) {
+            return Ok(Utc.from_utc_datetime(&ts).fixed_offset());
+        }
+    }
+
+    match NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        Ok(date) => Ok(Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()).fixed_offset()),
+        Err(_) => Err(format!("Cannot parse timestamp {:?}", s)),
+    }
+}
+
+fn from_epoch_millis(millis: f64) -> Result<Timestamp, String> {
+    let micros = (millis * 1_000.0).round();
+    if !micros.is_finite() || micros.abs() >= i64::MAX as f64 {
+        return Err(format!("Timestamp out of range: {}", millis));
+    }
+
+    Utc.timestamp_micros(micros as i64)
+        .single()
+        .map(|x| x.fixed_offset())
+        .ok_or(format!("Timestamp out of range: {}", millis))
+}
+
+pub fn epoch_millis(ts: &Timestamp) -> JsonNumber {
+    let micros = ts.timestamp_micros();
+    if micros % 1_000 == 0 {
+        JsonNumber::I64(micros / 1_000)
+    } else {
+        JsonNumber::F64(micros as f64 / 1_000.0)
+    }
+}
+
+/// RFC 3339 with as many fractional digits as needed, `Z` for UTC
+pub fn format_timestamp(ts: &Timestamp) -> String {
+    ts.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+/// strftime style format, checked first since chrono panics on invalid formats
+pub fn format_timestamp_with(ts: &Timestamp, format: &str) -> Result<String, String> {
+    let items = StrftimeItems::new(format).collect::<Vec<Item>>();
+    if items.iter().any(|x| matches!(x, Item::Error)) {
+        return Err(format!("Invalid timestamp format {:?}", format));
+    }
+    Ok(ts.format_with_items(items.into_iter()).to_string())
+}
+
+/// Same instant in another zone, an IANA name such as `Europe/Paris`, `UTC` or an offset such as `+02:00`
+pub fn convert_timezone(ts: &Timestamp, zone: &str) -> Result<Timestamp, String> {
+    let zone = zone.trim();
+    let offset = if zone.eq_ignore_ascii_case("UTC") || zone == "Z" {
+        Utc.fix()
+    } else if let Ok(offset) = zone.parse::<FixedOffset>() {
+        offset
+    } else {
+        let tz = zone.parse::<Tz>().map_err(|_| format!("Unknown time zone {:?}", zone))?;
+        tz.offset_from_utc_datetime(&ts.naive_utc()).fix()
+    };
+
+    Ok(ts.with_timezone(&offset))
+}
+
+pub fn add_interval(ts: &Timestamp, interval: &Interval) -> Result<Timestamp, String> {
+    let months = u32::try_from(interval.months.unsigned_abs()).ok().map(Months::new);
+    let with_months = match months {
+        Some(months) if interval.months >= 0 => ts.checked_add_months(months),
+        Some(months) => ts.checked_sub_months(months),
+        None => None,
+    };
+
+    with_months
+        .and_then(|x| x.checked_add_signed(Duration::microseconds(interval.micros)))
+        .ok_or("Timestamp out of range".to_string())
+}
+
+pub fn sub_timestamps(ts1: &Timestamp, ts2: &Timestamp) -> Result<Interval, String> {
+    ts1.signed_duration_since(*ts2)
+        .num_microseconds()
+        .map(|micros| Interval { months: 0, micros })
+        .ok_or("Interval out of range".to_string())
+}
+
+/// Units of DATE_TRUNC and DATE_PART are read case insensitively, singular or plural
+fn unit_name(unit: &str) -> String {
+    let unit = unit.trim().to_lowercase();
+    match unit.strip_suffix('s') {
+        Some(singular) if !singular.is_empty() => singular.to_string(),
+        _ => unit,
+    }
+}
+
+/// Truncates to the start of the unit in the timestamp's own offset, weeks start on Monday
+pub fn truncate(ts: &Timestamp, unit: &str) -> Result<Timestamp, String> {
+    let local = ts.naive_local();
+    let date = local.date();
+    let (year, month) = (date.year(), date.month());
+
+    let truncated = match unit_name(unit).as_str() {
+        "microsecond" => local.with_nanosecond(local.nanosecond() / 1_000 * 1_000),
+        "millisecond" => local.with_nanosecond(local.nanosecond() / 1_000_000 * 1_000_000),
+        "second" => local.with_nanosecond(0),
+        "minute" => date.and_hms_opt(local.hour(), local.minute(), 0),
+        "hour" => date.and_hms_opt(local.hour(), 0, 0),
+        "day" => date.and_hms_opt(0, 0, 0),
+        "week" => (date - Duration::days(date.weekday().num_days_from_monday() as i64)).and_hms_opt(0, 0, 0),
+        "month" => NaiveDate::from_ymd_opt(year, month, 1).and_then(|x| x.and_hms_opt(0, 0, 0)),
+        "quarter" => NaiveDate::from_ymd_opt(year, (month - 1) / 3 * 3 + 1, 1).and_then(|x| x.and_hms_opt(0, 0, 0)),
+        "year" => NaiveDate::from_ymd_opt(year, 1, 1).and_then(|x| x.and_hms_opt(0, 0, 0)),
+        _ => return Err(format!("Unknown DATE_TRUNC unit {:?}", unit)),
+    };
+
+    truncated
+        .and_then(|x| ts.offset().from_local_datetime(&x).single())
+        .ok_or("Timestamp out of range".to_string())
+}
+
+/// Field of a timestamp in its own offset, `dow` counts from Sunday as 0 and
+/// `epoch` is seconds since 1970 with a fraction
+pub fn date_part(ts: &Timestamp, field: &str) -> Result<JsonNumber, String> {
+    let seconds = ts.second() as f64 + ts.nanosecond() as f64 / 1e9;
+    let part = match unit_name(field).as_str() {
+        "year" => ts.year() as f64,
+        "quarter" => ((ts.month() - 1) / 3 + 1) as f64,
+        "month" => ts.month() as f64,
+        "week" | "isoweek" => ts.iso_week().week() as f64,
+        "day" => ts.day() as f64,
+        "dow" | "dayofweek" => ts.weekday().num_days_from_sunday() as f64,
+        "isodow" => ts.weekday().number_from_monday() as f64,
+        "doy" | "dayofyear" => ts.ordinal() as f64,
+        "hour" => ts.hour() as f64,
+        "minute" => ts.minute() as f64,
+        "second" => seconds,
+        "millisecond" => seconds * 1e3,
+        "microsecond" => seconds * 1e6,
+        "epoch" => ts.timestamp_micros() as f64 / 1e6,
+        "timezone" => ts.offset().local_minus_utc() as f64,
+        _ => return Err(format!("Unknown DATE_PART field {:?}", field)),
+    };
+
+    Ok(JsonNumber::F64(part).to_integer())
+}
@@ -0,0 +1,185 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// One level of a topic filter, levels are separated by `/`
+#[derive(Debug, Clone, PartialEq)]
+pub enum TopicLevel {
+    Exact(String),
+    /// `+`, any single level
+    Single,
+    /// `{name}`, any single level, bound to `name` for the query to use
+    Capture(String),
+    /// `#`, any number of levels, none included. Only last.
+    Multi,
+}
+
+/// Topics a query reads from, the FROM clause as an MQTT topic filter. `+` and `#`
+/// don't match topics starting with `$` at their first level, neither do captures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicFilter {
+    pub levels: Vec<TopicLevel>,
+}
+
+impl FromStr for TopicFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("Topic filter can't be empty".to_string());
+        }
+
+        let parts = s.split('/').collect::<Vec<_>>();
+        let levels = parts.iter().enumerate().map(|(i, part)| match *part {
+            "+" => Ok(TopicLevel::Single),
+            _ if part.starts_with('{') && part.ends_with('}') && is_capture_name(&part[1..part.len() - 1]) => {
+                Ok(TopicLevel::Capture(part[1..part.len() - 1].to_string()))
+            },
+            _ if part.contains(['{', '}']) => Err(format!("Topic filter captures must be a whole level named like an identifier: {}", s)),
+            "#" if i == parts.len() - 1 => Ok(TopicLevel::Multi),
+            "#" => Err(format!("Topic filter can only end with #: {}", s)),
+            _ if part.contains(['+', '#']) => Err(format!("Topic filter wildcards must be a whole level: {}", s)),
+            _ => Ok(TopicLevel::Exact(part.to_string())),
+        }).collect::<Result<Vec<_>, String>>()?;
+
+        let filter = TopicFilter { levels };
+        let captures = filter.captures();
+        if let Some(name) = captures.iter().enumerate().find(|(i, x)| captures[..*i].contains(x)).map(|x| x.1) {
+            return Err(format!("Topic filter captures {} twice: {}", name, s));
+        }
+        Ok(filter)
+    }
+}
+
+fn is_capture_name(name: &str) -> bool {
+    name.chars().next().is_some_and(|x| x.is_ascii_alphabetic() || x == '_')
+        && name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_')
+}
+
+impl TopicFilter {
+    /// Names of the captures, in level order
+    pub fn captures(&self) -> Vec<&str> {
+        self.levels.iter().filter_map(|x| match x {
+            TopicLevel::Capture(name) => Some(name.as_str()),
+            _ => None,
+        }).collect()
+    }
+
+    /// Levels captured from the topic with the given levels by name, `None` when
+    /// the topic doesn't match
+    pub fn capture(&self, levels: &[&str]) -> Option<serde_json::Map<String, serde_json::Value>> {
+        if levels.first().is_some_and(|x| x.starts_with('$')) && !matches!(self.levels.first(), Some(TopicLevel::Exact(_))) {
+            return None;
+        }
+
+        let mut captured = serde_json::Map::new();
+        for (i, filter_level) in self.levels.iter().enumerate() {
+            match (filter_level, levels.get(i)) {
+                (TopicLevel::Multi, _) => return Some(captured),
+                (TopicLevel::Exact(expected), Some(level)) if expected == level => {},
+                (TopicLevel::Single, Some(_)) => {},
+                (TopicLevel::Capture(name), Some(level)) => {
+                    captured.insert(name.clone(), serde_json::Value::String(level.to_string()));
+                },
+                _ => return None,
+            }
+        }
+        (levels.len() == self.levels.len()).then_some(captured)
+    }
+}
+
+impl fmt::Display for TopicFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let levels = self.levels.iter().map(|x| match x {
+            TopicLevel::Exact(level) => level.clone(),
+            TopicLevel::Single => "+".to_string(),
+            TopicLevel::Capture(name) => format!("{{{}}}", name),
+            TopicLevel::Multi => "#".to_string(),
+        }).collect::<Vec<_>>();
+        write!(f, "{}", levels.join("/"))
+    }
+}
+
+/// Levels of the topic of a message, which can't hold wildcards
+pub fn topic_levels(topic: &str) -> Result<Vec<&str>, String> {
+    if topic.is_empty() || topic.contains(['+', '#']) {
+        return Err(format!("Invalid topic: {}", topic));
+    }
+    Ok(topic.split('/').collect())
+}
+
+#[derive(Debug, Clone)]
+struct TopicNode<T> {
+    exact: HashMap<String, TopicNode<T>>,
+    single: Option<Box<TopicNode<T>>>,
+    /// Values of filters ending with `#` here
+    multi: Vec<T>,
+    /// Values of filters ending here
+    values: Vec<T>,
+}
+
+impl<T> Default for TopicNode<T> {
+    fn default() -> Self {
+        TopicNode { exact: HashMap::new(), single: None, multi: Vec::new(), values: Vec::new() }
+    }
+}
+
+/// Values stored under topic filters, looked up by topic one level at a time, so
+/// a lookup only visits the filters sharing a prefix with the topic however many
+/// are stored
+#[derive(Debug, Clone)]
+pub struct TopicTree<T> {
+    root: TopicNode<T>,
+}
+
+impl<T> Default for TopicTree<T> {
+    fn default() -> Self {
+        TopicTree { root: TopicNode::default() }
+    }
+}
+
+impl<T> TopicTree<T> {
+    pub fn insert(&mut self, filter: &TopicFilter, value: T) {
+        let mut node = &mut self.root;
+        for level in &filter.levels {
+            node = match level {
+                TopicLevel::Exact(level) => node.exact.entry(level.clone()).or_default(),
+                TopicLevel::Single | TopicLevel::Capture(_) => node.single.get_or_insert_with(Default::default),
+                TopicLevel::Multi => {
+                    node.multi.push(value);
+                    return;
+                },
+            };
+        }
+        node.values.push(value);
+    }
+
+    /// Values of every filter matching the topic with the given levels
+    pub fn matches(&self, levels: &[&str]) -> Vec<&T> {
+        let mut found = Vec::new();
+        let wildcards = !levels.first().is_some_and(|x| x.starts_with('$'));
+        Self::collect(&self.root, levels, wildcards, &mut found);
+        found
+    }
+
+    fn collect<'a>(node: &'a TopicNode<T>, levels: &[&str], wildcards: bool, found: &mut Vec<&'a T>) {
+        if wildcards {
+            found.extend(&node.multi);
+        }
+
+        let (level, rest) = match levels.split_first() {
+            Some(split) => split,
+            None => {
+                found.extend(&node.values);
+                return;
+            },
+        };
+
+        if let Some(child) = node.exact.get(*level) {
+            Self::collect(child, rest, true, found);
+        }
+        if let Some(child) = node.single.as_deref().filter(|_| wildcards) {
+            Self::collect(child, rest, true, found);
+        }
+    }
+}
@@ -1,4 +1,5 @@
-use std::vec;
+use std::{cmp::Ordering, collections::{HashMap, HashSet}, sync::Arc, vec};
+use regex::Regex;
 use sqlparser::ast::{BinaryOperator, UnaryOperator, Statement};
 
 use petgraph::{
@@ -6,25 +7,313 @@ use petgraph::{
     stable_graph::StableDiGraph,
 };
 
-use super::graph;
+use crate::json_math::JsonNumber;
+
+use super::{functions, graph, pattern, time, topic::TopicFilter, window::WindowSpec};
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum SQLLiteral {
     Integer(i64),
     Float(f64),
     String(String),
+    Bool(bool),
+    Null,
+    Array(Vec<SQLLiteral>),
+    Object(Vec<(String, SQLLiteral)>),
+    Timestamp(time::Timestamp),
+    Interval(time::Interval),
+}
+
+impl SQLLiteral {
+    /// Value of the literal, `None` when it holds a float that is not finite
+    pub fn to_value(&self) -> Option<SQLValue> {
+        match self {
+            SQLLiteral::Timestamp(ts) => Some(SQLValue::Timestamp(*ts)),
+            SQLLiteral::Interval(interval) => Some(SQLValue::Interval(*interval)),
+            _ => self.to_json().map(SQLValue::Json),
+        }
+    }
+
+    /// JSON value of the literal, timestamps and intervals nested in arrays
+    /// or objects are written as strings
+    pub fn to_json(&self) -> Option<serde_json::Value> {
+        match self {
+            SQLLiteral::Timestamp(ts) => Some(serde_json::Value::String(time::format_timestamp(ts))),
+            SQLLiteral::Interval(interval) => Some(serde_json::Value::String(interval.to_string())),
+            SQLLiteral::Integer(i) => Some(serde_json::Value::Number(serde_json::Number::from(*i))),
+            SQLLiteral::Float(f) => serde_json::Number::from_f64(*f).map(serde_json::Value::Number),
+            SQLLiteral::String(s) => Some(serde_json::Value::String(s.clone())),
+            SQLLiteral::Bool(b) => Some(serde_json::Value::Bool(*b)),
+            SQLLiteral::Null => Some(serde_json::Value::Null),
+            SQLLiteral::Array(elems) => elems.iter().map(|x| x.to_json()).collect::<Option<Vec<_>>>().map(serde_json::Value::Array),
+            SQLLiteral::Object(members) => members.iter()
+                .map(|(key, value)| value.to_json().map(|value| (key.clone(), value)))
+                .collect::<Option<serde_json::Map<_, _>>>()
+                .map(serde_json::Value::Object),
+        }
+    }
+}
+
+
+/// One step of an accessor path. Indexes count from the end when negative and
+/// slices leave out their end, `None` is an open bound. `Wildcard` matches every
+/// element or member and `Descendant` a key at any depth below.
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum AccessorSegment {
+    Key(String),
+    Index(i64),
+    Slice(Option<i64>, Option<i64>),
+    Wildcard,
+    Descendant(String),
+}
+
+impl AccessorSegment {
+    /// Paths with a step that can match more than once return an array of matches
+    pub fn is_multi(&self) -> bool {
+        matches!(self, AccessorSegment::Wildcard | AccessorSegment::Descendant(_))
+    }
+}
+
+impl std::fmt::Display for AccessorSegment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bound = |x: &Option<i64>| x.map_or(String::new(), |x| x.to_string());
+        match self {
+            AccessorSegment::Key(key) => write!(f, ".{}", key),
+            AccessorSegment::Index(index) => write!(f, "[{}]", index),
+            AccessorSegment::Slice(start, end) => write!(f, "[{}:{}]", bound(start), bound(end)),
+            AccessorSegment::Wildcard => write!(f, "[*]"),
+            AccessorSegment::Descendant(key) => write!(f, "..{}", key),
+        }
+    }
 }
 
+/// Target of a CAST, one of the JSON types with integers kept apart from other numbers
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum CastType {
+    Bool,
+    Integer,
+    Number,
+    String,
+    Array,
+    Object,
+    Json,
+    Timestamp,
+    Interval,
+}
+
+/// How a pattern is matched, SQL patterns are translated to anchored regexes
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
+pub enum MatchKind {
+    Like { escape: Option<char> },
+    ILike { escape: Option<char> },
+    SimilarTo { escape: Option<char> },
+    Regex,
+}
+
+/// Value of a task node while a query executes.
+///
+/// `Missing` is a field that is absent from the input data, distinct from a
+/// field that is present and holds a JSON `null`. Both are UNKNOWN in conditions.
+///
+/// How the two states propagate:
+/// - accessors: an absent key, or a key on a non object, is MISSING; a key on NULL is NULL
+/// - arithmetic, comparison, `||` and strict functions: MISSING if any operand is MISSING,
+///   otherwise NULL if any operand is NULL
+/// - AND, OR, XOR and NOT: MISSING is UNKNOWN like NULL and unknown results are NULL
+/// - `IS NULL` is TRUE for NULL and MISSING, `IS MISSING` is TRUE only for MISSING
+/// - COALESCE and IFNULL skip both states, NULLIF returns MISSING for a MISSING first argument
+/// - query results and WHERE conditions report MISSING as NULL
+///
+/// Timestamps and intervals only exist while executing, results write them as strings.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SQLValue {
+    Missing,
+    Json(serde_json::Value),
+    Timestamp(time::Timestamp),
+    Interval(time::Interval),
+}
+
+impl SQLValue {
+    /// JSON value, `None` for MISSING, timestamps and intervals
+    pub fn as_json(&self) -> Option<&serde_json::Value> {
+        match self {
+            SQLValue::Json(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn is_missing(&self) -> bool {
+        *self == SQLValue::Missing
+    }
+
+    /// NULL or MISSING
+    pub fn is_unknown(&self) -> bool {
+        match self {
+            SQLValue::Json(value) => value.is_null(),
+            SQLValue::Missing => true,
+            SQLValue::Timestamp(_) | SQLValue::Interval(_) => false,
+        }
+    }
+
+    pub fn into_json(self) -> serde_json::Value {
+        match self {
+            SQLValue::Json(value) => value,
+            SQLValue::Missing => serde_json::Value::Null,
+            SQLValue::Timestamp(ts) => serde_json::Value::String(time::format_timestamp(&ts)),
+            SQLValue::Interval(interval) => serde_json::Value::String(interval.to_string()),
+        }
+    }
+
+    /// Ordering across every type: MISSING, NULL, booleans, numbers, strings,
+    /// timestamps, intervals, arrays and then objects. Values of the same type
+    /// compare as usual, arrays element by element and objects by their members
+    /// sorted by key. Intervals compare by length like `<` does, those of the same
+    /// length by their months and then the rest.
+    pub fn total_cmp(&self, other: &SQLValue) -> Ordering {
+        match (self, other) {
+            (SQLValue::Json(a), SQLValue::Json(b)) => json_total_cmp(a, b),
+            (SQLValue::Timestamp(a), SQLValue::Timestamp(b)) => a.cmp(b),
+            (SQLValue::Interval(a), SQLValue::Interval(b)) => {
+                a.approx_micros().cmp(&b.approx_micros()).then_with(|| (a.months, a.micros).cmp(&(b.months, b.micros)))
+            },
+            _ => self.type_rank().cmp(&other.type_rank()),
+        }
+    }
+
+    fn type_rank(&self) -> u8 {
+        match self {
+            SQLValue::Missing => 0,
+            SQLValue::Json(value) => json_type_rank(value),
+            SQLValue::Timestamp(_) => 5,
+            SQLValue::Interval(_) => 6,
+        }
+    }
+
+    /// MISSING if any of the values is MISSING, otherwise NULL if any is NULL
+    pub fn unknown_of(values: &[&SQLValue]) -> Option<SQLValue> {
+        if values.iter().any(|x| x.is_missing()) {
+            Some(SQLValue::Missing)
+        } else if values.iter().any(|x| x.is_unknown()) {
+            Some(SQLValue::Json(serde_json::Value::Null))
+        } else {
+            None
+        }
+    }
+}
+
+fn json_type_rank(value: &serde_json::Value) -> u8 {
+    match value {
+        serde_json::Value::Null => 1,
+        serde_json::Value::Bool(_) => 2,
+        serde_json::Value::Number(_) => 3,
+        serde_json::Value::String(_) => 4,
+        serde_json::Value::Array(_) => 7,
+        serde_json::Value::Object(_) => 8,
+    }
+}
+
+fn json_total_cmp(a: &serde_json::Value, b: &serde_json::Value) -> Ordering {
+    match (a, b) {
+        (serde_json::Value::Bool(a), serde_json::Value::Bool(b)) => a.cmp(b),
+        (serde_json::Value::Number(a), serde_json::Value::Number(b)) => {
+            JsonNumber::from(a).partial_cmp(&JsonNumber::from(b)).unwrap_or(Ordering::Equal)
+        },
+        (serde_json::Value::String(a), serde_json::Value::String(b)) => a.cmp(b),
+        (serde_json::Value::Array(a), serde_json::Value::Array(b)) => {
+            a.iter().zip(b).map(|(a, b)| json_total_cmp(a, b))
+                .find(|x| *x != Ordering::Equal)
+                .unwrap_or(a.len().cmp(&b.len()))
+        },
+        (serde_json::Value::Object(a), serde_json::Value::Object(b)) => {
+            let mut a = a.iter().collect::<Vec<_>>();
+            let mut b = b.iter().collect::<Vec<_>>();
+            a.sort_by(|x, y| x.0.cmp(y.0));
+            b.sort_by(|x, y| x.0.cmp(y.0));
+            a.iter().zip(&b).map(|(a, b)| a.0.cmp(b.0).then_with(|| json_total_cmp(a.1, b.1)))
+                .find(|x| *x != Ordering::Equal)
+                .unwrap_or(a.len().cmp(&b.len()))
+        },
+        _ => json_type_rank(a).cmp(&json_type_rank(b)),
+    }
+}
+
+/// Hashable form of a value for grouping and DISTINCT, equal for values `=` finds
+/// equal: whole numbers are the same key whichever JSON type holds them, objects
+/// don't depend on member order, and MISSING is a key of its own apart from NULL
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ValueKey {
+    Missing,
+    Null,
+    Bool(bool),
+    Integer(i128),
+    /// Bits of a number that isn't whole
+    Float(u64),
+    String(String),
+    Timestamp(chrono::NaiveDateTime),
+    Interval(i64, i64),
+    Array(Vec<ValueKey>),
+    /// Members sorted by key
+    Object(Vec<(String, ValueKey)>),
+}
+
+impl From<&SQLValue> for ValueKey {
+    fn from(value: &SQLValue) -> Self {
+        match value {
+            SQLValue::Missing => ValueKey::Missing,
+            SQLValue::Json(value) => ValueKey::from(value),
+            SQLValue::Timestamp(ts) => ValueKey::Timestamp(ts.naive_utc()),
+            SQLValue::Interval(interval) => ValueKey::Interval(interval.months, interval.micros),
+        }
+    }
+}
+
+impl From<&serde_json::Value> for ValueKey {
+    fn from(value: &serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => ValueKey::Null,
+            serde_json::Value::Bool(b) => ValueKey::Bool(*b),
+            serde_json::Value::Number(n) => match JsonNumber::from(n) {
+                JsonNumber::U64(n) => ValueKey::Integer(n as i128),
+                JsonNumber::I64(n) => ValueKey::Integer(n as i128),
+                JsonNumber::F64(n) if (n as i128) as f64 == n => ValueKey::Integer(n as i128),
+                JsonNumber::F64(n) => ValueKey::Float(n.to_bits()),
+            },
+            serde_json::Value::String(s) => ValueKey::String(s.clone()),
+            serde_json::Value::Array(arr) => ValueKey::Array(arr.iter().map(ValueKey::from).collect()),
+            serde_json::Value::Object(map) => {
+                let mut members = map.iter().map(|(k, v)| (k.clone(), ValueKey::from(v))).collect::<Vec<_>>();
+                members.sort_by(|a, b| a.0.cmp(&b.0));
+                ValueKey::Object(members)
+            },
+        }
+    }
+}
+
+impl From<serde_json::Value> for SQLValue {
+    fn from(value: serde_json::Value) -> Self {
+        SQLValue::Json(value)
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum TaskAction {
     Literal(SQLLiteral),
     Link,
-    Accessor(Vec<String>),
+    Accessor(Vec<AccessorSegment>),
     UnaryOp(UnaryOperator),
     BinaryOp(BinaryOperator),
-    _Function(String),
+    /// Scalar function with its definition, looked up when the query is built
+    Function { name: String, def: &'static functions::FunctionDef },
+    /// Aggregate function, evaluated once over the rows of a FOREACH
+    Aggregate { name: String, distinct: bool },
+    Case { has_operand: bool, has_else: bool },
+    Cast { target: CastType, try_cast: bool },
+    InList { negated: bool },
+    Match { kind: MatchKind, negated: bool },
+    Between { negated: bool },
     Root,
+    /// Values the FROM topic captures from the topic of a message, by capture name
+    Topic,
     Finalize,
     Stale,
 }
@@ -47,8 +336,41 @@ pub struct QueryTask {
 #[derive(Debug)]
 pub struct QuerySelect {
     pub select_items: Vec<NodeIndex>,
-    pub from: String,
+    /// Keeps only the first of rows with deeply equal results
+    pub distinct: bool,
+    pub from: TopicFilter,
     pub where_expr: Option<NodeIndex>,
+    /// Keys rows are grouped by before they are aggregated
+    pub group_by: Vec<NodeIndex>,
+    /// Window messages are grouped into before their keys, only in SELECT
+    pub window: Option<WindowSpec>,
+    /// Condition on each group, computed from its keys and aggregates
+    pub having: Option<NodeIndex>,
+    pub order_by: Vec<SortKey>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+/// ORDER BY expression of the rows of a FOREACH
+#[derive(Debug, Clone)]
+pub struct SortKey {
+    pub expr: NodeIndex,
+    pub descending: bool,
+    /// NULL and MISSING sort before every other value, by default only when descending
+    pub nulls_first: bool,
+}
+
+impl SortKey {
+    pub fn compare(&self, a: &SQLValue, b: &SQLValue) -> Ordering {
+        match (a.is_unknown(), b.is_unknown()) {
+            (true, true) => Ordering::Equal,
+            (true, false) if self.nulls_first => Ordering::Less,
+            (true, false) => Ordering::Greater,
+            (false, true) => self.compare(b, a).reverse(),
+            (false, false) if self.descending => a.total_cmp(b).reverse(),
+            (false, false) => a.total_cmp(b),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -56,8 +378,18 @@ pub struct BuiltQuerySelect {
     pub task_graph: StableDiGraph<QueryTask, usize>,
     pub query_select: Option<QuerySelect>,
     pub sql_stmt : Option<Statement>,
+    /// Node of the message, its value is read from the message a run borrows
+    pub root: NodeIndex,
+    /// Node of the topic captures of the message
+    pub topic: Option<NodeIndex>,
     pub tasks: Vec<(NodeIndex, QueryTask)>,
-    pub json_context: Vec<serde_json::Value>,
+    /// Aggregate nodes in execution order, empty unless this is an aggregate query
+    pub aggregates: Vec<NodeIndex>,
+    /// Tasks computed from aggregates, run once after every row is aggregated
+    pub final_tasks: Vec<(NodeIndex, QueryTask)>,
+    pub patterns: HashMap<NodeIndex, Regex>,
+    /// Values of the nodes before a message runs, literals set and the rest MISSING
+    pub initial_context: Vec<SQLValue>,
 }
 
 #[derive(Debug)]
@@ -73,6 +405,55 @@ pub enum BuiltQuery {
     FOREACH(BuiltQueryForeach),
 }
 
+impl BuiltQuery {
+    /// Topic filter of the FROM clause
+    pub fn from(&self) -> Option<&TopicFilter> {
+        let select = match self {
+            BuiltQuery::SELECT(select) => select,
+            BuiltQuery::FOREACH(foreach) => &foreach.main,
+        };
+        select.query_select.as_ref().map(|x| &x.from)
+    }
+
+    /// Fresh values for running the query, see `ExecutionContext`
+    pub(super) fn new_context(self: &Arc<Self>) -> ExecutionContext {
+        let (main, foreach) = match &**self {
+            BuiltQuery::SELECT(select) => (select.initial_context.clone(), Vec::new()),
+            BuiltQuery::FOREACH(foreach) => (foreach.main.initial_context.clone(), foreach.foreach.initial_context.clone()),
+        };
+        ExecutionContext { query: Arc::clone(self), main, foreach }
+    }
+}
+
+/// Values of the nodes of a query as it runs. The query itself is never changed
+/// by running, a context holds everything that is, so each thread running a query
+/// needs its own. It is reused from one message to the next, which keeps its node
+/// vectors, and only holds the values computed from a message, the message itself
+/// is borrowed for the run. Only `CompiledQuery::new_context` makes one.
+#[derive(Debug, Clone)]
+pub struct ExecutionContext {
+    /// Query the context was made for, a context only fits the query it came from
+    pub(super) query: Arc<BuiltQuery>,
+    pub(super) main: Vec<SQLValue>,
+    /// Nodes of the FOREACH subquery, empty for a SELECT
+    pub(super) foreach: Vec<SQLValue>,
+}
+
+impl ExecutionContext {
+    /// Sets the topic captures for the messages run next, MISSING without a topic
+    pub(super) fn set_topic(&mut self, captures: SQLValue) {
+        let (main, foreach) = match &*self.query {
+            BuiltQuery::SELECT(select) => (select.topic, None),
+            BuiltQuery::FOREACH(foreach) => (foreach.main.topic, foreach.foreach.topic),
+        };
+        for (json_context, topic) in [(&mut self.main, main), (&mut self.foreach, foreach)] {
+            if let Some(value) = topic.and_then(|x| json_context.get_mut(x.index())) {
+                *value = captures.clone();
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct SimpleQueryResult {
     pub result : Vec<(String, serde_json::Value)>,
@@ -91,14 +472,45 @@ pub enum QueryResult {
     Nested(NestedQueryResult),
 }
 
+/// What a query over a stream gives for a message
+#[derive(Debug)]
+pub enum StreamOutput {
+    /// Result of the message, or of a window as it closes
+    Result(QueryResult),
+    /// Result of a closed window again, after a late message changed it
+    Correction(QueryResult),
+    /// Message too late for all of its windows, given back as it is
+    Late(serde_json::Value),
+}
+
+/// A condition passes only when it is TRUE, UNKNOWN (NULL) filters like FALSE.
+/// A query without a condition always passes.
+pub fn condition_passes(cond: &Option<serde_json::Value>) -> bool {
+    match cond {
+        Some(value) => value.as_bool().unwrap_or(false),
+        None => true
+    }
+}
+
+impl SimpleQueryResult {
+    pub fn passed(&self) -> bool {
+        condition_passes(&self.cond)
+    }
+}
+
 impl BuiltQuerySelect {
     pub fn new() -> Self {
         BuiltQuerySelect {
             task_graph: StableDiGraph::<QueryTask, usize>::new(),
             query_select: None,
             sql_stmt: None,
+            root: NodeIndex::new(0),
+            topic: None,
             tasks: vec![],
-            json_context: vec![]
+            aggregates: vec![],
+            final_tasks: vec![],
+            patterns: HashMap::new(),
+            initial_context: vec![]
         }
     }
 
@@ -115,7 +527,7 @@ impl BuiltQuerySelect {
         graph::populate_context(&mut self.task_graph)?;
         let task_order = graph::toposort(&self.task_graph)?;
 
-        let mut json_context = vec![serde_json::Value::Null; task_order.iter().max().unwrap().index() + 1];
+        let mut json_context = vec![SQLValue::Missing; task_order.iter().max().unwrap().index() + 1];
 
         // Initialize literals
         let literal_init_success = json_context.iter_mut().enumerate().map(|(i, val)| -> Result<(), NodeIndex> {
@@ -123,20 +535,9 @@ impl BuiltQuerySelect {
             if self.task_graph.contains_node(node_idx) {
                 match &self.task_graph[node_idx].action {
                     TaskAction::Literal(literal) => {
-                        match literal {
-                            SQLLiteral::Integer(i) => {
-                                *val = serde_json::Value::Number(serde_json::Number::from(*i));
-                            },
-                            SQLLiteral::Float(f) => {
-                                let number = serde_json::Number::from_f64(*f);
-                                if number.is_none()  {
-                                    return Err(node_idx);
-                                }
-                                *val = serde_json::Value::Number(number.unwrap());
-                            },
-                            SQLLiteral::String(s) => {
-                                *val = serde_json::Value::String(s.clone());
-                            }
+                        match literal.to_value() {
+                            Some(value) => *val = value,
+                            None => return Err(node_idx),
                         }
                     },
                     _ => {}
@@ -151,22 +552,128 @@ impl BuiltQuerySelect {
             return Err(format!("Contains non finite floating point literals: {:?}", literal_init_errors));
         }
 
+        let after_aggregate = self.check_aggregation(&task_order)?;
+
         let tasks : Vec<_> = task_order.iter().filter(|&&x| {
             match self.task_graph[x].action {
                 TaskAction::Accessor(_) => true,
                 TaskAction::Link => true,
                 TaskAction::UnaryOp(_) => true,
                 TaskAction::BinaryOp(_) => true,
-                TaskAction::_Function(_) => true,
+                TaskAction::Function { .. } => true,
+                TaskAction::Case { .. } => true,
+                TaskAction::Cast { .. } => true,
+                TaskAction::InList { .. } => true,
+                TaskAction::Match { .. } => true,
+                TaskAction::Between { .. } => true,
                 _ => false,
             }
         }).map(|&idx| (idx, self.task_graph[idx].clone()))
         .collect();
 
+        let (final_tasks, tasks): (Vec<_>, Vec<_>) = tasks.into_iter().partition(|(idx, _)| after_aggregate.contains(idx));
         self.tasks = tasks;
-        self.json_context = json_context;
+        self.final_tasks = final_tasks;
+        self.aggregates = task_order.iter()
+            .filter(|&&x| matches!(self.task_graph[x].action, TaskAction::Aggregate { .. }))
+            .copied()
+            .collect();
+        self.patterns = self.compile_patterns()?;
+        self.initial_context = json_context;
         Ok(())
     }
+
+    /// Aggregate queries reduce their rows to one result, or one per group
+    pub fn is_aggregate(&self) -> bool {
+        !self.aggregates.is_empty() || self.is_grouped() || self.is_windowed()
+    }
+
+    /// Windowed queries aggregate over messages and only run on a stream
+    pub fn is_windowed(&self) -> bool {
+        self.query_select.as_ref().map_or(false, |x| x.window.is_some())
+    }
+
+    pub fn is_grouped(&self) -> bool {
+        self.query_select.as_ref().map_or(false, |x| !x.group_by.is_empty())
+    }
+
+    /// Finds the nodes computed from aggregates and checks where aggregates are used.
+    /// Aggregates can't be nested or used in the condition or GROUP BY, and in an
+    /// aggregate query select items and HAVING are computed from aggregates and
+    /// constants only, keys being read through `ANY_VALUE` by `graph::link_group_keys`.
+    fn check_aggregation(&self, task_order: &[NodeIndex]) -> Result<HashSet<NodeIndex>, String> {
+        let mut after_aggregate = HashSet::new();
+        let mut per_row = HashSet::new();
+
+        for idx in task_order {
+            let is_aggregate = matches!(self.task_graph[*idx].action, TaskAction::Aggregate { .. });
+            for parent in self.task_graph.neighbors_directed(*idx, petgraph::Direction::Incoming) {
+                let parent_aggregated = after_aggregate.contains(&parent) || matches!(self.task_graph[parent].action, TaskAction::Aggregate { .. });
+                if parent_aggregated && is_aggregate {
+                    return Err("Aggregate functions can't be nested".to_string());
+                }
+                if parent_aggregated {
+                    after_aggregate.insert(*idx);
+                }
+                if per_row.contains(&parent) && !is_aggregate {
+                    per_row.insert(*idx);
+                }
+            }
+            if matches!(self.task_graph[*idx].action, TaskAction::Root | TaskAction::Topic) {
+                per_row.insert(*idx);
+            }
+        }
+
+        if let Some(query_select) = &self.query_select {
+            let window_time = query_select.window.as_ref().map(|x| x.time);
+            for idx in query_select.where_expr.iter().chain(query_select.group_by.iter()).chain(window_time.iter()) {
+                if after_aggregate.contains(idx) || matches!(self.task_graph[*idx].action, TaskAction::Aggregate { .. }) {
+                    return Err("Aggregate functions are not allowed in WHERE, WHEN, GROUP BY or window times".to_string());
+                }
+            }
+
+            let has_aggregates = !query_select.group_by.is_empty() || query_select.window.is_some()
+                || task_order.iter().any(|x| matches!(self.task_graph[*x].action, TaskAction::Aggregate { .. }));
+            for (i, item) in query_select.select_items.iter().enumerate() {
+                if has_aggregates && per_row.contains(item) {
+                    return Err(format!("Select item {} must be an aggregate, a GROUP BY key or computed from them", i + 1));
+                }
+            }
+            if query_select.having.map_or(false, |x| per_row.contains(&x)) {
+                return Err("HAVING must be computed from aggregates and GROUP BY keys".to_string());
+            }
+            if has_aggregates && query_select.order_by.iter().any(|x| per_row.contains(&x.expr)) {
+                return Err("ORDER BY must be computed from aggregates and GROUP BY keys".to_string());
+            }
+        }
+
+        Ok(after_aggregate)
+    }
+
+    /// Compiles patterns given as literals once for the query, keyed by the
+    /// node matching them. Other patterns are compiled for every row.
+    fn compile_patterns(&self) -> Result<HashMap<NodeIndex, Regex>, String> {
+        let mut patterns = HashMap::new();
+
+        for (idx, task) in &self.tasks {
+            let (kind, pattern_idx) = match (&task.action, &task.context) {
+                (TaskAction::Match { kind, .. }, Some(TaskContext::DualParent(_, pattern_idx))) => (kind.clone(), *pattern_idx),
+                (TaskAction::Function { def, .. }, Some(TaskContext::MultiParent(parents))) => {
+                    if !def.is_pattern() || parents.len() < 2 {
+                        continue;
+                    }
+                    (MatchKind::Regex, parents[1])
+                },
+                _ => continue
+            };
+
+            if let TaskAction::Literal(SQLLiteral::String(pattern)) = &self.task_graph[pattern_idx].action {
+                patterns.insert(*idx, pattern::compile(&kind, pattern)?);
+            }
+        }
+
+        Ok(patterns)
+    }
 }
 
 impl BuiltQueryForeach{
@@ -0,0 +1,352 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::{TimeZone, Utc};
+use petgraph::graph::NodeIndex;
+use sqlparser::ast::{Expr, FunctionArg, FunctionArgExpr, Value};
+
+use super::aggregate::{Accumulator, Groups};
+use super::graph;
+use super::time::{self, Timestamp};
+use super::types::ValueKey;
+
+/// Windows a query groups its messages into by event time, from
+/// `GROUP BY TUMBLE(time, size)`, `HOP(time, slide, size)` or `SESSION(time, gap)`
+#[derive(Debug, Clone)]
+pub struct WindowSpec {
+    /// Node computing the event time of a message
+    pub time: NodeIndex,
+    pub kind: WindowKind,
+}
+
+/// Window lengths in microseconds
+#[derive(Debug, Clone, PartialEq)]
+pub enum WindowKind {
+    /// Back to back windows, every message is in exactly one
+    Tumble { size: i64 },
+    /// Windows starting every `slide`, overlapping when `slide` is shorter than `size`
+    Hop { slide: i64, size: i64 },
+    /// Windows of each group key that stay open until `gap` passes without a message
+    Session { gap: i64 },
+}
+
+impl WindowKind {
+    /// Start and end of the fixed windows an event time falls in, in start order.
+    /// Sessions depend on the messages before, see `WindowState::session_mut`.
+    pub fn assign(&self, time: i64) -> Vec<(i64, i64)> {
+        match self {
+            WindowKind::Session { .. } => Vec::new(),
+            WindowKind::Tumble { size } => {
+                let start = time.div_euclid(*size) * size;
+                vec![(start, start.saturating_add(*size))]
+            },
+            WindowKind::Hop { slide, size } => {
+                let mut windows = Vec::new();
+                let mut start = time.div_euclid(*slide) * slide;
+                while start.saturating_add(*size) > time {
+                    windows.push((start, start.saturating_add(*size)));
+                    start -= slide;
+                }
+                windows.reverse();
+                windows
+            },
+        }
+    }
+}
+
+/// Window function of a GROUP BY expression with its event time expression,
+/// `None` when the expression isn't one
+pub fn window_function(expr: &Expr) -> Option<Result<(Expr, WindowKind), String>> {
+    let func = match expr {
+        Expr::Function(func) => func,
+        _ => return None,
+    };
+
+    let name = func.name.to_string().to_uppercase();
+    let arg_count = match name.as_str() {
+        "TUMBLE" | "SESSION" => 2,
+        "HOP" => 3,
+        _ => return None,
+    };
+
+    let args = func.args.iter().map(|x| match x {
+        FunctionArg::Unnamed(FunctionArgExpr::Expr(arg)) => Ok(arg.clone()),
+        _ => Err(format!("Unsupported {} argument: {}", name, x)),
+    }).collect::<Result<Vec<_>, String>>();
+
+    let args = match args {
+        Ok(args) if args.len() == arg_count => args,
+        Ok(_) => return Some(Err(format!("{} expects {} arguments: {}", name, arg_count, expr))),
+        Err(err) => return Some(Err(err)),
+    };
+
+    let lengths = args[1..].iter().map(window_length).collect::<Result<Vec<_>, String>>();
+    Some(lengths.map(|lengths| {
+        let kind = match lengths[..] {
+            [gap] if name == "SESSION" => WindowKind::Session { gap },
+            [size] => WindowKind::Tumble { size },
+            [slide, size] => WindowKind::Hop { slide, size },
+            _ => unreachable!(),
+        };
+        (args[0].clone(), kind)
+    }))
+}
+
+/// Length of an interval literal or string, windows need a fixed positive length
+fn window_length(expr: &Expr) -> Result<i64, String> {
+    let interval = match expr {
+        Expr::Interval(interval) => graph::interval_literal(interval)?,
+        Expr::Value(Value::SingleQuotedString(s)) => time::parse_interval(s)?,
+        _ => return Err(format!("Window length must be an interval literal: {}", expr)),
+    };
+
+    if interval.months != 0 || interval.micros <= 0 {
+        return Err(format!("Window length must be a positive number of days or less: {}", expr));
+    }
+    Ok(interval.micros)
+}
+
+fn from_micros(micros: i64) -> Result<Timestamp, String> {
+    Utc.timestamp_micros(micros)
+        .single()
+        .map(|x| x.fixed_offset())
+        .ok_or(format!("Window bound out of range: {} microseconds", micros))
+}
+
+/// How a windowed query treats messages that arrive out of event time order.
+/// Lengths are in microseconds and not negative.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Lateness {
+    /// How far the watermark trails the latest event time, so messages up to this
+    /// much out of order are still on time
+    pub out_of_orderness: i64,
+    /// How long past the watermark closed windows are kept for `LatePolicy::Correct`,
+    /// zero for the other policies
+    pub allowed_lateness: i64,
+    pub policy: LatePolicy,
+}
+
+/// What happens to a message arriving after the windows it belongs to closed
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum LatePolicy {
+    /// The message is ignored
+    #[default]
+    Drop,
+    /// The message is added to its closed windows while they are kept, each gives
+    /// its result again. Messages later than that are dropped.
+    Correct,
+    /// The message is given back on its own once it is too late for all of its
+    /// windows, a message still taken by some of them is only added to those
+    SideOutput,
+}
+
+/// Groups of the messages of one window
+#[derive(Debug, Clone)]
+pub struct Window {
+    pub start: i64,
+    pub end: i64,
+    pub groups: Groups,
+    /// Closed windows already gave their result and are only kept for corrections
+    pub closed: bool,
+}
+
+impl Window {
+    fn new(start: i64, end: i64, empty: &[Accumulator]) -> Self {
+        Window { start, end, groups: Groups::new(empty.to_vec()), closed: false }
+    }
+
+    /// Adds the messages of a window starting after this one, covering both
+    fn merge(&mut self, other: Window) -> Result<(), String> {
+        self.groups.merge(other.groups)?;
+        self.start = self.start.min(other.start);
+        self.end = self.end.max(other.end);
+        self.closed = self.closed || other.closed;
+        Ok(())
+    }
+
+    /// Groups with WINDOW_START and WINDOW_END set to the bounds the window closed with
+    pub fn into_groups(self) -> Result<Groups, String> {
+        let (start, end) = (from_micros(self.start)?, from_micros(self.end)?);
+        let mut groups = self.groups;
+        groups.accumulators = groups.accumulators.into_iter()
+            .map(|x| x.into_iter().map(|x| x.in_window(start, end)).collect())
+            .collect();
+        Ok(groups)
+    }
+}
+
+/// Window held by `WindowState`, sessions are found through their key
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum WindowRef {
+    Fixed(i64),
+    Sessions(Vec<ValueKey>),
+}
+
+/// Windows of a query between messages. Windows close once the watermark reaches
+/// their end, a message for a window that closed is late and handled by the
+/// late policy.
+#[derive(Debug, Clone, Default)]
+pub struct WindowState {
+    pub windows: BTreeMap<i64, Window>,
+    /// Sessions by group key, each holding that key's group only. A key is evicted
+    /// with its last session.
+    pub sessions: HashMap<Vec<ValueKey>, Vec<Window>>,
+    /// Windows by the event time they close at, or are let go of once closed, so
+    /// the watermark only visits the windows it reaches. A window whose end moved
+    /// is added again under its new time, the old entry finds nothing to do.
+    deadlines: BTreeMap<i64, Vec<WindowRef>>,
+    /// Latest event time seen
+    pub max_time: Option<i64>,
+    pub lateness: Lateness,
+}
+
+impl WindowState {
+    pub fn new(lateness: Lateness) -> Self {
+        WindowState { lateness, ..Default::default() }
+    }
+
+    /// Event time every message before is expected to have arrived by
+    pub fn watermark(&self) -> Option<i64> {
+        self.max_time.map(|x| x.saturating_sub(self.lateness.out_of_orderness))
+    }
+
+    fn is_closed(&self, end: i64) -> bool {
+        self.watermark().is_some_and(|x| end <= x)
+    }
+
+    /// How long past the watermark closed windows are kept
+    fn kept(&self) -> i64 {
+        match self.lateness.policy {
+            LatePolicy::Correct => self.lateness.allowed_lateness,
+            LatePolicy::Drop | LatePolicy::SideOutput => 0,
+        }
+    }
+
+    fn schedule(&mut self, window: &Window, window_ref: WindowRef) {
+        let deadline = if window.closed { window.end.saturating_add(self.kept()) } else { window.end };
+        self.deadlines.entry(deadline).or_default().push(window_ref);
+    }
+
+    /// Window starting at `start`, opened empty. `None` when the window closed and
+    /// isn't kept anymore.
+    pub fn window_mut(&mut self, start: i64, end: i64, empty: &[Accumulator]) -> Option<&mut Window> {
+        if !self.windows.contains_key(&start) {
+            if self.is_closed(end) {
+                return None;
+            }
+            let window = Window::new(start, end, empty);
+            self.schedule(&window, WindowRef::Fixed(start));
+            self.windows.insert(start, window);
+        }
+        self.windows.get_mut(&start)
+    }
+
+    /// Session of `key` a message at `time` belongs to. The message joins every
+    /// session of its key it is within `gap` of, merging them into one session
+    /// stretched over the message and the gap after it, otherwise it starts a new
+    /// one. A session merged with a closed one stays closed. `None` when the new
+    /// session would already be closed.
+    pub fn session_mut(&mut self, key: &[ValueKey], time: i64, gap: i64, empty: &[Accumulator]) -> Result<Option<&mut Window>, String> {
+        let end = time.saturating_add(gap);
+        // Sessions that only touch are joined too, nothing closes between them
+        let overlaps = |x: &Window| time <= x.end && end >= x.start;
+
+        let joins = self.sessions.get(key).is_some_and(|x| x.iter().any(overlaps));
+        if !joins && self.is_closed(end) {
+            return Ok(None);
+        }
+
+        let sessions = self.sessions.entry(key.to_vec()).or_default();
+        let (mut joined, others): (Vec<_>, Vec<_>) = std::mem::take(sessions).into_iter().partition(overlaps);
+        *sessions = others;
+
+        joined.sort_by_key(|x| x.start);
+        let before = (joined.len() == 1).then(|| (joined[0].end, joined[0].closed));
+        let mut joined = joined.into_iter();
+        let mut session = joined.next().unwrap_or_else(|| Window::new(time, end, empty));
+        for other in joined {
+            session.merge(other)?;
+        }
+        session.start = session.start.min(time);
+        session.end = session.end.max(end);
+
+        if before != Some((session.end, session.closed)) {
+            self.schedule(&session, WindowRef::Sessions(key.to_vec()));
+        }
+        let sessions = self.sessions.entry(key.to_vec()).or_default();
+        sessions.push(session);
+        Ok(sessions.last_mut())
+    }
+
+    /// Number of windows and sessions held, open or kept for corrections
+    pub fn window_count(&self) -> usize {
+        self.windows.len() + self.sessions.values().map(|x| x.len()).sum::<usize>()
+    }
+
+    /// Moves the latest event time to `time` if it is later, returning the windows
+    /// the watermark closes in the order they close, by end and then start. They
+    /// are kept for corrections until the watermark is the allowed lateness past
+    /// their end.
+    pub fn advance(&mut self, time: i64) -> Vec<Window> {
+        self.max_time = Some(self.max_time.map_or(time, |x| x.max(time)));
+        let watermark = self.watermark().unwrap_or(i64::MIN);
+        let kept = self.kept();
+
+        let due = match watermark.checked_add(1) {
+            Some(after) => {
+                let later = self.deadlines.split_off(&after);
+                std::mem::replace(&mut self.deadlines, later)
+            },
+            None => std::mem::take(&mut self.deadlines),
+        };
+
+        let mut closed = Vec::new();
+        let mut visited = HashSet::new();
+        for window_ref in due.into_values().flatten() {
+            if !visited.insert(window_ref.clone()) {
+                continue;
+            }
+
+            let mut take = |window: &mut Window| {
+                if !window.closed && window.end <= watermark {
+                    window.closed = true;
+                    closed.push(window.clone());
+                    if window.end.saturating_add(kept) > watermark {
+                        self.deadlines.entry(window.end.saturating_add(kept)).or_default().push(window_ref.clone());
+                    }
+                }
+                !window.closed || window.end.saturating_add(kept) > watermark
+            };
+
+            match &window_ref {
+                WindowRef::Fixed(start) => {
+                    if self.windows.get_mut(start).is_some_and(|x| !take(x)) {
+                        self.windows.remove(start);
+                    }
+                },
+                WindowRef::Sessions(key) => {
+                    if let Some(sessions) = self.sessions.get_mut(key) {
+                        sessions.retain_mut(take);
+                        if sessions.is_empty() {
+                            self.sessions.remove(key);
+                        }
+                    }
+                },
+            }
+        }
+
+        closed.sort_by_key(|x| (x.end, x.start));
+        closed
+    }
+
+    /// Closes every open window and lets go of all of them, in the order they close
+    pub fn close_all(&mut self) -> Vec<Window> {
+        let windows = std::mem::take(&mut self.windows).into_values();
+        let sessions = std::mem::take(&mut self.sessions).into_values().flatten();
+        self.deadlines.clear();
+
+        let mut closed = windows.chain(sessions).filter(|x| !x.closed).collect::<Vec<_>>();
+        closed.iter_mut().for_each(|x| x.closed = true);
+        closed.sort_by_key(|x| (x.end, x.start));
+        closed
+    }
+}